@@ -43,6 +43,8 @@ pub mod traps {
         pub const MACHINEL_EXTERNAL: usize = 1 << 11;
         /// Supervisor guest external interrupt.
         pub const SUPERVISOR_GUEST_EXTERNEL: usize = 1 << 12;
+        /// Local counter overflow interrupt (Sscofpmf).
+        pub const LOCAL_COUNTER_OVERFLOW: usize = 1 << 13;
     }
 
     /// Constants about exception.
@@ -97,6 +99,8 @@ pub mod traps {
         pub const S_TIMER: usize = INTC_IRQ_BASE + 5;
         /// Supervisor external interrupt in `scause`
         pub const S_EXT: usize = INTC_IRQ_BASE + 9;
+        /// Local counter overflow interrupt in `scause`
+        pub const S_LCOF: usize = INTC_IRQ_BASE + 13;
         /// The maximum number of IRQs.
         pub const MAX_IRQ_COUNT: usize = 1024;
         /// The timer IRQ number (supervisor timer interrupt in `scause`).
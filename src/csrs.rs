@@ -0,0 +1,72 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CSRs that are not (yet) provided by the `riscv` and `riscv-h` crates.
+
+/// Hypervisor Environment Configuration Register.
+pub mod henvcfg {
+    use riscv::{read_csr_as_usize, write_csr_as_usize};
+
+    read_csr_as_usize!(0x60a);
+    write_csr_as_usize!(0x60a);
+
+    /// Landing Pad Enable, enables Zicfilp landing pads in VS-mode.
+    pub const LPE: usize = 1 << 2;
+    /// Shadow Stack Enable, enables Zicfiss shadow stacks in VS-mode.
+    pub const SSE: usize = 1 << 3;
+    /// Pointer Masking Mode of VS-mode, provided by Ssnpm.
+    pub const PMM: usize = 0b11 << 32;
+    /// `PMM` value for a pointer masking length of 7 bits.
+    pub const PMM_PMLEN_7: usize = 0b10 << 32;
+    /// `PMM` value for a pointer masking length of 16 bits.
+    pub const PMM_PMLEN_16: usize = 0b11 << 32;
+    /// Double Trap Enable, provided by Ssdbltrp.
+    pub const DTE: usize = 1 << 59;
+    /// Hardware A/D bit updating of VS-stage page tables, provided by Svadu.
+    pub const ADUE: usize = 1 << 61;
+    /// STimecmp Enable, allows VS-mode to access `stimecmp` (i.e. `vstimecmp`) with Sstc.
+    pub const STCE: usize = 1 << 63;
+}
+
+/// Hypervisor Counter-Enable Register.
+///
+/// The one in `riscv-h` has the wrong CSR number.
+pub mod hcounteren {
+    use riscv::{read_csr_as_usize, write_csr_as_usize};
+
+    read_csr_as_usize!(0x606);
+    write_csr_as_usize!(0x606);
+
+    /// Lets VS/VU-mode read `cycle`.
+    pub const CY: usize = 1 << 0;
+    /// Lets VS/VU-mode read `time`.
+    pub const TM: usize = 1 << 1;
+    /// Lets VS/VU-mode read `instret`.
+    pub const IR: usize = 1 << 2;
+}
+
+/// Virtual Supervisor Timer Compare Register, provided by Sstc.
+pub mod vstimecmp {
+    use riscv::{read_csr_as_usize, write_csr_as_usize};
+
+    read_csr_as_usize!(0x24d);
+    write_csr_as_usize!(0x24d);
+}
+
+/// Supervisor Count Overflow Register, provided by Sscofpmf.
+pub mod scountovf {
+    use riscv::read_csr_as_usize;
+
+    read_csr_as_usize!(0xda0);
+}
@@ -41,6 +41,50 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+/// Detect if the Sstc extension exists on current hart environment
+///
+/// This function tries to read stimecmp and returns false if the read operation failed.
+pub fn detect_sstc_extension() -> bool {
+    // run detection by trap on csrr instruction.
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x14d", out(reg) _, options(nomem, nostack)); // 0x14d => stimecmp
+    });
+    // return the answer from output flag. 0 => success, 2 => failed, illegal instruction
+    ans != 2
+}
+
+/// Detect if the Sscofpmf extension exists on current hart environment
+///
+/// This function tries to read scountovf and returns false if the read operation failed.
+pub fn detect_sscofpmf_extension() -> bool {
+    // run detection by trap on csrr instruction.
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0xda0", out(reg) _, options(nomem, nostack)); // 0xda0 => scountovf
+    });
+    // return the answer from output flag. 0 => success, 2 => failed, illegal instruction
+    ans != 2
+}
+
+/// Enables the injection of virtual local counter overflow interrupts through `hvip` on the
+/// current hart, returning whether the hart supports it.
+///
+/// This needs the `hvien` CSR of the AIA extension. The function tries to set its LCOFI bit, and
+/// returns false if the CSR does not exist or the bit is read-only zero.
+pub fn enable_lcofi_injection() -> bool {
+    let mut hvien: usize = 0;
+    // run detection by trap on csrrs instruction, leaving `hvien` 0 if it traps.
+    with_detect_trap(0, || unsafe {
+        asm!(
+            "csrs  0x608, {bit}", // 0x608 => hvien
+            "csrr  {}, 0x608",
+            out(reg) hvien,
+            bit = in(reg) crate::consts::traps::interrupt::LOCAL_COUNTER_OVERFLOW,
+            options(nomem, nostack),
+        );
+    });
+    hvien & crate::consts::traps::interrupt::LOCAL_COUNTER_OVERFLOW != 0
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
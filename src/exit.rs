@@ -0,0 +1,166 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axaddrspace::GuestPhysAddr;
+use sbi_spec::{binary::SbiRet, hsm::suspend_type, srst};
+
+/// RISC-V specific details of a vCPU exit, see [`RISCVVCpu::exit_detail`](crate::RISCVVCpu::exit_detail).
+///
+/// [`AxVCpuExitReason`](axvcpu::AxVCpuExitReason) is shared by all architectures and cannot
+/// express some SBI requests, so they are reported as the closest generic exit reason plus one of
+/// these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RISCVExitDetail {
+    /// The vCPU suspended itself with SBI `HART_SUSPEND`, reported as
+    /// [`Halt`](axvcpu::AxVCpuExitReason::Halt).
+    ///
+    /// The VMM runs the vCPU again once it has a pending interrupt. A retentive suspend returns
+    /// from the SBI call, a non-retentive one restarts at the resume address given by the guest.
+    HartSuspend(HartSuspendType),
+    /// The guest requested a system reset with SBI `SYSTEM_RESET` or the legacy `SHUTDOWN`,
+    /// reported as [`SystemDown`](axvcpu::AxVCpuExitReason::SystemDown).
+    SystemReset {
+        /// Whether to shut down or reboot the VM.
+        reset_type: SystemResetType,
+        /// Why the guest requested the reset.
+        reason: SystemResetReason,
+    },
+    /// The guest suspended the VM with SBI `SYSTEM_SUSPEND`, reported as
+    /// [`Halt`](axvcpu::AxVCpuExitReason::Halt). All other vCPUs of the VM are stopped.
+    ///
+    /// The vCPU is already set up to resume, the VMM runs it again to wake the VM up.
+    SystemSuspend {
+        /// The sleep state requested by the guest.
+        sleep_type: SystemSleepType,
+        /// Where the vCPU resumes.
+        resume_addr: GuestPhysAddr,
+        /// The value the vCPU finds in `a1` when it resumes.
+        opaque: usize,
+    },
+    /// The guest made an SBI call that the [`SbiPolicy`](crate::SbiPolicy) or an
+    /// [`SbiExtension`](crate::SbiExtension) hands to the VMM, reported as
+    /// [`Hypercall`](axvcpu::AxVCpuExitReason::Hypercall) with `a0`-`a5` as `args` and an `nr`
+    /// encoding the extension and function IDs, see [`SBI_CALL_NR_FLAG`](crate::SBI_CALL_NR_FLAG).
+    ///
+    /// The VMM completes the call like a hypercall, with
+    /// [`RISCVVCpu::complete_hypercall`](crate::RISCVVCpu::complete_hypercall).
+    SbiCall {
+        /// The extension ID of the call.
+        extension_id: usize,
+        /// The function ID of the call.
+        function_id: usize,
+    },
+}
+
+/// The type of an SBI `HART_SUSPEND`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartSuspendType {
+    /// The hart state is preserved, like `WFI`.
+    Retentive,
+    /// The hart state is lost, so the vCPU may be power-gated.
+    NonRetentive,
+}
+
+impl HartSuspendType {
+    /// Decodes the `suspend_type` argument of `HART_SUSPEND`, failing with the SBI error to
+    /// return to the guest.
+    ///
+    /// Platform specific suspend types are not supported.
+    pub(crate) fn from_raw(raw: usize) -> Result<Self, SbiRet> {
+        match u32::try_from(raw) {
+            Ok(suspend_type::RETENTIVE) => Ok(Self::Retentive),
+            Ok(suspend_type::NON_RETENTIVE) => Ok(Self::NonRetentive),
+            Ok(0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff) => {
+                Err(SbiRet::not_supported())
+            }
+            _ => Err(SbiRet::invalid_param()),
+        }
+    }
+}
+
+/// The `reset_type` of an SBI `SYSTEM_RESET`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemResetType {
+    /// Power off the VM.
+    Shutdown,
+    /// Power cycle the VM.
+    ColdReboot,
+    /// Reboot the VM, possibly keeping some state such as memory.
+    WarmReboot,
+}
+
+impl SystemResetType {
+    /// Decodes the `reset_type` argument of `SYSTEM_RESET`, failing with the SBI error to return
+    /// to the guest.
+    ///
+    /// Vendor specific reset types are not supported.
+    pub(crate) fn from_raw(raw: usize) -> Result<Self, SbiRet> {
+        match u32::try_from(raw) {
+            Ok(srst::RESET_TYPE_SHUTDOWN) => Ok(Self::Shutdown),
+            Ok(srst::RESET_TYPE_COLD_REBOOT) => Ok(Self::ColdReboot),
+            Ok(srst::RESET_TYPE_WARM_REBOOT) => Ok(Self::WarmReboot),
+            Ok(0xf000_0000..=0xffff_ffff) => Err(SbiRet::not_supported()),
+            _ => Err(SbiRet::invalid_param()),
+        }
+    }
+}
+
+/// The `reset_reason` of an SBI `SYSTEM_RESET`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemResetReason {
+    /// A regular shutdown or reboot.
+    NoReason,
+    /// The guest failed, e.g. its kernel panicked.
+    SystemFailure,
+    /// A SBI implementation specific reason.
+    SbiSpecific(u32),
+    /// A vendor or platform specific reason.
+    Vendor(u32),
+}
+
+impl SystemResetReason {
+    /// Decodes the `reset_reason` argument of `SYSTEM_RESET`, failing with the SBI error to
+    /// return to the guest.
+    pub(crate) fn from_raw(raw: usize) -> Result<Self, SbiRet> {
+        match u32::try_from(raw) {
+            Ok(srst::RESET_REASON_NO_REASON) => Ok(Self::NoReason),
+            Ok(srst::RESET_REASON_SYSTEM_FAILURE) => Ok(Self::SystemFailure),
+            Ok(reason @ 0xe000_0000..=0xefff_ffff) => Ok(Self::SbiSpecific(reason)),
+            Ok(reason @ 0xf000_0000..=0xffff_ffff) => Ok(Self::Vendor(reason)),
+            _ => Err(SbiRet::invalid_param()),
+        }
+    }
+}
+
+/// The `sleep_type` of an SBI `SYSTEM_SUSPEND`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemSleepType {
+    /// Suspend to RAM.
+    SuspendToRam,
+}
+
+impl SystemSleepType {
+    /// Decodes the `sleep_type` argument of `SYSTEM_SUSPEND`, failing with the SBI error to return
+    /// to the guest.
+    ///
+    /// Platform specific sleep types are not supported.
+    pub(crate) fn from_raw(raw: usize) -> Result<Self, SbiRet> {
+        match u32::try_from(raw) {
+            Ok(0) => Ok(Self::SuspendToRam),
+            Ok(0x8000_0000..=0xffff_ffff) => Err(SbiRet::not_supported()),
+            _ => Err(SbiRet::invalid_param()),
+        }
+    }
+}
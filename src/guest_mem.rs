@@ -93,3 +93,17 @@ pub(crate) fn fetch_guest_instruction(gva: GuestVirtAddr) -> u32 {
     };
     inst
 }
+
+/// Decodes the guest physical address of a region of `len` bytes that an SBI call passes as
+/// `lo` and `hi`.
+///
+/// `hi` holds the upper XLEN bits of the address, which must be 0 on RV64. Returns `None` if the
+/// address is not aligned to `align` or the region does not fit in the guest physical address
+/// space.
+pub(crate) fn shmem_gpa(lo: usize, hi: usize, len: usize, align: usize) -> Option<GuestPhysAddr> {
+    if hi != 0 || lo % align != 0 {
+        return None;
+    }
+    lo.checked_add(len)?;
+    Some(GuestPhysAddr::from(lo))
+}
@@ -0,0 +1,74 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoding of the hart masks passed to SBI calls.
+//!
+//! Guest hart IDs are vCPU IDs, so a decoded hart mask is a bitmask of vCPU IDs of the VM.
+
+use axvisor_api::vmm::MAX_VCPU_NUM;
+
+/// Decodes a `hart_mask`/`hart_mask_base` pair (SBI v0.2+) into a bitmask of vCPU IDs.
+///
+/// A `hart_mask_base` of `usize::MAX` selects all `vcpu_num` vCPUs. Returns `None` if the mask
+/// selects a hart that does not exist, in which case the call fails with `INVALID_PARAM`.
+pub(crate) fn decode_hart_mask(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    vcpu_num: usize,
+) -> Option<u64> {
+    let vcpu_num = vcpu_num.min(MAX_VCPU_NUM);
+    let all = if vcpu_num == u64::BITS as usize {
+        u64::MAX
+    } else {
+        (1u64 << vcpu_num) - 1
+    };
+
+    if hart_mask_base == usize::MAX {
+        return Some(all);
+    }
+    if hart_mask == 0 {
+        return Some(0);
+    }
+
+    // The highest hart selected by the mask must exist.
+    let highest = hart_mask_base.checked_add((usize::BITS - 1 - hart_mask.leading_zeros()) as _)?;
+    if highest >= vcpu_num {
+        return None;
+    }
+    Some((hart_mask as u64) << hart_mask_base)
+}
+
+/// Reads the hart mask of a legacy (SBI v0.1) call from the guest virtual address `addr`, and
+/// decodes it into a bitmask of vCPU IDs.
+///
+/// A NULL `addr` selects all `vcpu_num` vCPUs, e.g. for `sbi_remote_fence_i(NULL)` of older
+/// Linux.
+///
+/// Returns `Err(())` if the guest memory cannot be read, or `Ok(None)` if the mask selects a hart
+/// that does not exist.
+#[cfg(feature = "legacy")]
+pub(crate) fn read_legacy_hart_mask(addr: usize, vcpu_num: usize) -> Result<Option<u64>, ()> {
+    use crate::guest_mem;
+    use axaddrspace::GuestVirtAddr;
+
+    if addr == 0 {
+        return Ok(decode_hart_mask(0, usize::MAX, vcpu_num));
+    }
+
+    let mut buf = [0u8; size_of::<usize>()];
+    if guest_mem::copy_from_guest_va(&mut buf, GuestVirtAddr::from(addr)) != buf.len() {
+        return Err(());
+    }
+    Ok(decode_hart_mask(usize::from_ne_bytes(buf), 0, vcpu_num))
+}
@@ -0,0 +1,98 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axerrno::{AxResult, ax_err};
+use axvisor_api::vmm::{VCpuId, VMId};
+use sbi_spec::binary::SbiRet;
+
+use crate::{SbiExtension, sbi_console};
+
+/// Host services needed by the RISC-V vCPU that are not covered by `axvisor_api`.
+///
+/// Implement it with [`crate_interface::impl_interface`]. Every method has a default, so a host
+/// only has to implement the methods it cares about.
+#[crate_interface::def_interface]
+pub trait RISCVVCpuHostIf {
+    /// Returns the hart ID of the physical CPU the caller is running on.
+    ///
+    /// Used to direct remote fences at the harts that currently run the target vCPUs. If it
+    /// returns `None`, remote fences are broadcast to all harts instead.
+    fn current_hart_id() -> Option<usize> {
+        None
+    }
+}
+
+/// The backend of the guest debug console, i.e. the SBI DBCN extension and the legacy
+/// `CONSOLE_PUTCHAR`/`CONSOLE_GETCHAR` calls.
+///
+/// Implement it with [`crate_interface::impl_interface`] to route each guest to its own virtual
+/// UART, log file or buffer. By default, guest console I/O goes to the host's SBI console.
+#[crate_interface::def_interface]
+pub trait RISCVGuestConsoleIf {
+    /// Writes bytes the given vCPU sent to its console, returning how many were written.
+    fn write(vm_id: VMId, vcpu_id: VCpuId, bytes: &[u8]) -> AxResult<usize> {
+        let _ = (vm_id, vcpu_id);
+        sbi_ret_to_result(sbi_console::passthrough_write(bytes))
+    }
+
+    /// Reads pending console input for the given vCPU into `buf`, returning how many bytes were
+    /// read. Must not block, returning 0 if there is no input.
+    fn read(vm_id: VMId, vcpu_id: VCpuId, buf: &mut [u8]) -> AxResult<usize> {
+        let _ = (vm_id, vcpu_id);
+        sbi_ret_to_result(sbi_console::passthrough_read(buf))
+    }
+}
+
+/// SBI extension providers shared by all vCPUs, see [`SbiExtension`].
+///
+/// Implement it with [`crate_interface::impl_interface`] to serve extension ID ranges for every
+/// vCPU without registering providers on each of them. Providers registered on a vCPU with
+/// [`RISCVVCpu::register_sbi_extension`](crate::RISCVVCpu::register_sbi_extension) take
+/// precedence.
+#[crate_interface::def_interface]
+pub trait RISCVSbiExtensionIf {
+    /// Returns the provider of the extension `extension_id`, if any.
+    fn sbi_extension(extension_id: usize) -> Option<&'static dyn SbiExtension> {
+        let _ = extension_id;
+        None
+    }
+}
+
+fn sbi_ret_to_result(ret: SbiRet) -> AxResult<usize> {
+    if ret.is_ok() {
+        Ok(ret.value)
+    } else {
+        ax_err!(Io, "host SBI console failed")
+    }
+}
+
+/// Returns the hart ID of the current physical CPU, if the host provides it.
+pub(crate) fn current_hart_id() -> Option<usize> {
+    crate_interface::call_interface!(RISCVVCpuHostIf::current_hart_id())
+}
+
+/// Returns the global provider of the extension `extension_id`, if the host has one.
+pub(crate) fn sbi_extension(extension_id: usize) -> Option<&'static dyn SbiExtension> {
+    crate_interface::call_interface!(RISCVSbiExtensionIf::sbi_extension(extension_id))
+}
+
+/// Writes guest console output through the console backend.
+pub(crate) fn console_write(vm_id: VMId, vcpu_id: VCpuId, bytes: &[u8]) -> AxResult<usize> {
+    crate_interface::call_interface!(RISCVGuestConsoleIf::write(vm_id, vcpu_id, bytes))
+}
+
+/// Reads guest console input through the console backend.
+pub(crate) fn console_read(vm_id: VMId, vcpu_id: VCpuId, buf: &mut [u8]) -> AxResult<usize> {
+    crate_interface::call_interface!(RISCVGuestConsoleIf::read(vm_id, vcpu_id, buf))
+}
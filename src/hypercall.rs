@@ -0,0 +1,141 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
+use axvisor_api::vmm::{VCpuId, VMId};
+use sbi_spec::binary::SbiRet;
+
+use crate::guest_mem;
+
+/// A hypercall handler, see [`RISCVVCpu::register_hypercall`](crate::RISCVVCpu::register_hypercall).
+///
+/// The returned [`SbiRet`] is written to `a0` (error) and `a1` (value) of the calling vCPU.
+pub type HypercallHandler = Arc<dyn Fn(&HypercallContext) -> SbiRet + Send + Sync>;
+
+/// A hypercall (`EID_HVC` ecall), or a call to an [`SbiExtension`](crate::SbiExtension), being
+/// handled inside the vCPU.
+#[derive(Debug)]
+pub struct HypercallContext {
+    vm_id: VMId,
+    vcpu_id: VCpuId,
+    extension_id: usize,
+    function_id: usize,
+    args: [usize; 6],
+}
+
+impl HypercallContext {
+    pub(crate) fn new(
+        vm_id: VMId,
+        vcpu_id: VCpuId,
+        extension_id: usize,
+        function_id: usize,
+        args: [usize; 6],
+    ) -> Self {
+        Self {
+            vm_id,
+            vcpu_id,
+            extension_id,
+            function_id,
+            args,
+        }
+    }
+
+    /// Returns the ID of the calling VM.
+    pub fn vm_id(&self) -> VMId {
+        self.vm_id
+    }
+
+    /// Returns the ID of the calling vCPU.
+    pub fn vcpu_id(&self) -> VCpuId {
+        self.vcpu_id
+    }
+
+    /// Returns the extension ID of the call, passed in `a7`, i.e. [`EID_HVC`](crate::EID_HVC) for
+    /// hypercalls.
+    pub fn extension_id(&self) -> usize {
+        self.extension_id
+    }
+
+    /// Returns the function ID of the hypercall, passed in `a6`.
+    pub fn function_id(&self) -> usize {
+        self.function_id
+    }
+
+    /// Returns the arguments of the hypercall, passed in `a0`-`a5`.
+    pub fn args(&self) -> &[usize; 6] {
+        &self.args
+    }
+
+    /// Returns the `n`th argument of the hypercall, passed in `a<n>`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is not below 6.
+    pub fn arg(&self, n: usize) -> usize {
+        self.args[n]
+    }
+
+    /// Copies guest memory at the guest physical address `gpa` to `buf`, returning the number of
+    /// bytes copied.
+    pub fn read_guest_phys(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> usize {
+        guest_mem::copy_from_guest(buf, gpa)
+    }
+
+    /// Copies `buf` to guest memory at the guest physical address `gpa`, returning the number of
+    /// bytes copied.
+    pub fn write_guest_phys(&self, gpa: GuestPhysAddr, buf: &[u8]) -> usize {
+        guest_mem::copy_to_guest(buf, gpa)
+    }
+
+    /// Copies guest memory at the guest virtual address `gva` to `buf`, translated by the
+    /// calling vCPU's page table, returning the number of bytes copied.
+    pub fn read_guest_virt(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> usize {
+        guest_mem::copy_from_guest_va(buf, gva)
+    }
+
+    /// Copies `buf` to guest memory at the guest virtual address `gva`, translated by the calling
+    /// vCPU's page table, returning the number of bytes copied.
+    pub fn write_guest_virt(&self, gva: GuestVirtAddr, buf: &[u8]) -> usize {
+        guest_mem::copy_to_guest_va(buf, gva)
+    }
+}
+
+/// The hypercall handlers registered on a vCPU, by function ID.
+#[derive(Default)]
+pub(crate) struct HypercallRegistry {
+    handlers: BTreeMap<usize, HypercallHandler>,
+}
+
+impl HypercallRegistry {
+    /// Registers `handler` for `function_id`, returning `false` if one is already registered.
+    pub fn register(&mut self, function_id: usize, handler: HypercallHandler) -> bool {
+        if self.handlers.contains_key(&function_id) {
+            return false;
+        }
+        self.handlers.insert(function_id, handler);
+        true
+    }
+
+    /// Removes the handler of `function_id`, returning it if there was one.
+    pub fn unregister(&mut self, function_id: usize) -> Option<HypercallHandler> {
+        self.handlers.remove(&function_id)
+    }
+
+    /// Returns the handler of `function_id`, if any.
+    pub fn get(&self, function_id: usize) -> Option<&HypercallHandler> {
+        self.handlers.get(&function_id)
+    }
+}
@@ -14,6 +14,7 @@
 
 #![no_std]
 #![feature(doc_cfg)]
+#![feature(linkage)]
 #![feature(riscv_ext_intrinsics)]
 #![doc = include_str!("../README.md")]
 
@@ -21,20 +22,47 @@
 extern crate log;
 extern crate alloc;
 
+use alloc::sync::Arc;
+
 mod consts;
+mod csrs;
 /// The Control and Status Registers (CSRs) for a RISC-V hypervisor.
 mod detect;
+mod exit;
 mod guest_mem;
+mod hart_mask;
+mod host;
+mod hypercall;
 mod percpu;
 mod regs;
+mod sbi_base;
 mod sbi_console;
+mod sbi_ext;
+mod sbi_fwft;
+mod sbi_pmu;
+mod sbi_policy;
+mod sbi_rfence;
+mod sbi_sse;
+mod sbi_sta;
+mod timer;
 mod trap;
 mod vcpu;
+mod vm;
 
+pub use self::exit::{
+    HartSuspendType, RISCVExitDetail, SystemResetReason, SystemResetType, SystemSleepType,
+};
+pub use self::host::{RISCVGuestConsoleIf, RISCVSbiExtensionIf, RISCVVCpuHostIf};
+pub use self::hypercall::{HypercallContext, HypercallHandler};
 pub use self::percpu::RISCVPerCpu;
+pub use self::sbi_base::SbiIdentity;
+pub use self::sbi_ext::{SbiExtension, SbiExtensionResult};
+pub use self::sbi_policy::{SbiAction, SbiPolicy};
 pub use self::vcpu::RISCVVCpu;
+pub use self::vm::{HartState, RISCVVmState};
 pub use detect::detect_h_extension as has_hardware_support;
 pub use regs::GprIndex;
+pub use sbi_spec::binary::SbiRet;
 
 /// Extension ID for hypercall, defined by ourselves.
 /// `0x48`, `0x56`, `0x43` is "HVC" in ASCII.
@@ -42,21 +70,39 @@ pub use regs::GprIndex;
 /// Borrowed from the design of `eid_from_str` in [sbi-spec](https://github.com/rustsbi/rustsbi/blob/62ab2e498ca66cdf75ce049c9dbc2f1862874553/sbi-spec/src/lib.rs#L51)
 pub const EID_HVC: usize = 0x485643;
 
+/// Set in the `nr` of a [`Hypercall`](axvcpu::AxVCpuExitReason::Hypercall) exit that hands an SBI
+/// call to the VMM with [`RISCVExitDetail::SbiCall`], rather than an [`EID_HVC`] hypercall.
+///
+/// Such an `nr` is `SBI_CALL_NR_FLAG | extension_id << 32 | function_id`, with the extension ID
+/// truncated to 31 bits and the function ID to 32 bits. Hypercalls with this bit set in their
+/// function ID fail with `SBI_ERR_INVALID_PARAM`, so the two never collide.
+pub const SBI_CALL_NR_FLAG: u64 = 1 << 63;
+
 /// Configuration for creating a new `RISCVVCpu`
+///
+/// The guest sees each vCPU as the hart whose hart ID is the vCPU ID, in `a0` at boot and in all
+/// SBI calls that address harts.
 #[derive(Clone, Debug)]
 pub struct RISCVVCpuCreateConfig {
-    /// The ID of the vCPU, default to `0`.
-    pub hart_id: usize,
     /// The physical address of the device tree blob.
     /// Default to `0x9000_0000`.
     pub dtb_addr: usize,
+    /// The state shared by all vCPUs of the VM.
+    ///
+    /// All vCPUs of a VM must be created with the same instance.
+    pub vm_state: Arc<RISCVVmState>,
 }
 
-impl Default for RISCVVCpuCreateConfig {
-    fn default() -> Self {
+impl RISCVVCpuCreateConfig {
+    /// Creates the configuration of a vCPU of the VM whose shared state is `vm_state`, with the
+    /// default device tree address.
+    ///
+    /// There is no `Default`, as a vCPU with a state of its own would not see the other vCPUs of
+    /// its VM, e.g. skip them in remote fences.
+    pub fn new(vm_state: Arc<RISCVVmState>) -> Self {
         Self {
-            hart_id: 0,
             dtb_addr: 0x9000_0000,
+            vm_state,
         }
     }
 }
@@ -19,7 +19,8 @@ use riscv::register::sie;
 use riscv_h::register::{hedeleg, hideleg, hvip};
 
 use crate::consts::traps;
-use crate::has_hardware_support;
+use crate::csrs::hcounteren;
+use crate::{detect, has_hardware_support};
 
 /// Risc-V per-CPU state.
 pub struct RISCVPerCpu;
@@ -50,20 +51,22 @@ impl AxArchPerCpu for RISCVPerCpu {
     }
 }
 
+/// The synchronous exceptions delegated to all guests.
+///
+/// vCPUs may delegate more, see [`RISCVVCpu`](crate::RISCVVCpu)'s FWFT support.
+pub(crate) const HEDELEG: usize = traps::exception::INST_ADDR_MISALIGN
+    | traps::exception::BREAKPOINT
+    | traps::exception::ENV_CALL_FROM_U_OR_VU
+    | traps::exception::INST_PAGE_FAULT
+    | traps::exception::LOAD_PAGE_FAULT
+    | traps::exception::STORE_PAGE_FAULT
+    | traps::exception::ILLEGAL_INST;
+
 /// Initialize (H)S-level CSRs to a reasonable state.
 unsafe fn setup_csrs() {
     unsafe {
         // Delegate some synchronous exceptions.
-        hedeleg::Hedeleg::from_bits(
-            traps::exception::INST_ADDR_MISALIGN
-                | traps::exception::BREAKPOINT
-                | traps::exception::ENV_CALL_FROM_U_OR_VU
-                | traps::exception::INST_PAGE_FAULT
-                | traps::exception::LOAD_PAGE_FAULT
-                | traps::exception::STORE_PAGE_FAULT
-                | traps::exception::ILLEGAL_INST,
-        )
-        .write();
+        hedeleg::Hedeleg::from_bits(HEDELEG).write();
 
         // Delegate all interupts.
         hideleg::Hideleg::from_bits(
@@ -73,15 +76,17 @@ unsafe fn setup_csrs() {
         )
         .write();
 
+        // Counter overflows are taken in HS-mode, guests get theirs injected through `hvip`
+        // where the hart supports it.
+        detect::enable_lcofi_injection();
+
         // Clear all interrupts.
         hvip::clear_vssip();
         hvip::clear_vstip();
         hvip::clear_vseip();
 
-        // clear all interrupts.
-        // the csr num of hcounteren is 0x606, the riscv repo is error!!!
-        // hcounteren::Hcounteren::from_bits(0xffff_ffff).write();
-        core::arch::asm!("csrw {csr}, {rs}", csr = const 0x606, rs = in(reg) -1);
+        // Guests read the fixed counters directly, and the programmable ones they own once bound.
+        hcounteren::write(hcounteren::CY | hcounteren::TM | hcounteren::IR);
 
         // enable interrupt
         sie::set_sext();
@@ -185,7 +185,7 @@ impl GuestVsCsrs {
         self.vscause = vscause::read().bits();
         self.vstval = vstval::read();
         self.vsatp = vsatp::read().bits();
-        // vstimecmp is not a CSR but a memory-mapped register.
+        // vstimecmp is only implemented with Sstc, see `RISCVVCpu::unbind`.
     }
 }
 
@@ -197,16 +197,23 @@ pub struct GuestVirtualHsCsrs {
     pub hie: usize,
     pub hgeie: usize,
     pub hgatp: usize,
+    /// Virtual interrupts pending for the guest. Kept in sync with the hardware `hvip` while the
+    /// vCPU is bound.
+    pub hvip: usize,
+    /// Environment configuration for the guest, e.g. whether it may use `stimecmp` directly.
+    pub henvcfg: usize,
 }
 
 impl GuestVirtualHsCsrs {
     /// Load the virtualized HS-level CSRs from hardware into this structure.
     pub fn load_from_hw(&mut self) {
-        use riscv_h::register::{hgatp, hgeie, hie};
+        use riscv_h::register::{hgatp, hgeie, hie, hvip};
 
         self.hie = hie::read().bits();
         self.hgeie = hgeie::read();
         self.hgatp = hgatp::read().bits();
+        self.hvip = hvip::read().bits();
+        self.henvcfg = crate::csrs::henvcfg::read();
     }
 }
 
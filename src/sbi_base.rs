@@ -0,0 +1,80 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use sbi_spec::{base, binary::SbiRet};
+
+/// The SBI implementation a VM sees through the Base extension.
+///
+/// The default reports SBI v3.0, which the vCPU implements, with [`Self::IMPL_ID_UNREGISTERED`]
+/// rather than the ID of another SBI implementation, and hides the identity of the physical harts
+/// by reporting 0 for `mvendorid`, `marchid` and `mimpid`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbiIdentity {
+    /// The SBI spec version, as `major << 24 | minor`.
+    pub spec_version: usize,
+    /// The SBI implementation ID.
+    pub impl_id: usize,
+    /// The SBI implementation version.
+    pub impl_version: usize,
+    /// The value of `mvendorid` reported to the guest.
+    pub mvendorid: usize,
+    /// The value of `marchid` reported to the guest.
+    pub marchid: usize,
+    /// The value of `mimpid` reported to the guest.
+    pub mimpid: usize,
+}
+
+impl Default for SbiIdentity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SbiIdentity {
+    /// An implementation ID that the SBI spec does not assign to any implementation.
+    pub const IMPL_ID_UNREGISTERED: usize = usize::MAX;
+
+    /// Creates the default identity.
+    pub const fn new() -> Self {
+        Self {
+            spec_version: 3 << 24,
+            impl_id: Self::IMPL_ID_UNREGISTERED,
+            impl_version: 0,
+            mvendorid: 0,
+            marchid: 0,
+            mimpid: 0,
+        }
+    }
+}
+
+/// Handles a Base extension call from the guest.
+///
+/// `probe` tells whether the vCPU handles the given extension.
+pub(crate) fn handle_base_ecall(
+    identity: &SbiIdentity,
+    function_id: usize,
+    param: [usize; 6],
+    probe: impl FnOnce(usize) -> bool,
+) -> SbiRet {
+    match function_id {
+        base::GET_SBI_SPEC_VERSION => SbiRet::success(identity.spec_version),
+        base::GET_SBI_IMPL_ID => SbiRet::success(identity.impl_id),
+        base::GET_SBI_IMPL_VERSION => SbiRet::success(identity.impl_version),
+        base::PROBE_EXTENSION => SbiRet::success(probe(param[0]) as usize),
+        base::GET_MVENDORID => SbiRet::success(identity.mvendorid),
+        base::GET_MARCHID => SbiRet::success(identity.marchid),
+        base::GET_MIMPID => SbiRet::success(identity.mimpid),
+        _ => SbiRet::not_supported(),
+    }
+}
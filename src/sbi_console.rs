@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use memory_addr::VirtAddr;
-use sbi_spec::binary::{Physical, SbiRet};
+use sbi_spec::{
+    binary::{Physical, SbiRet},
+    legacy,
+};
 
 pub const EID_DBCN: usize = 0x4442434e;
 pub const FID_CONSOLE_WRITE: usize = 0;
@@ -22,8 +25,6 @@ pub const FID_CONSOLE_WRITE_BYTE: usize = 2;
 
 /// SBI success state return value.
 pub const RET_SUCCESS: usize = 0;
-/// Error for SBI call failed for unknown reasons.
-pub const RET_ERR_FAILED: usize = -1isize as _;
 /// Error for target operation not supported.
 pub const RET_ERR_NOT_SUPPORTED: usize = -2isize as _;
 
@@ -47,6 +48,39 @@ pub fn console_read(buf: &mut [u8]) -> SbiRet {
     ))
 }
 
+/// Writes guest console output to the host console, the default console backend.
+///
+/// Uses the SBI debug console extension, falling back to the legacy `CONSOLE_PUTCHAR` if the
+/// host firmware does not implement it.
+pub fn passthrough_write(buf: &[u8]) -> SbiRet {
+    let ret = console_write(buf);
+    if ret.error != RET_ERR_NOT_SUPPORTED {
+        return ret;
+    }
+    for &byte in buf {
+        sbi_call_legacy_1(legacy::LEGACY_CONSOLE_PUTCHAR, byte as usize);
+    }
+    SbiRet::success(buf.len())
+}
+
+/// Reads guest console input from the host console, the default console backend.
+///
+/// Uses the SBI debug console extension, falling back to the legacy `CONSOLE_GETCHAR` if the
+/// host firmware does not implement it.
+pub fn passthrough_read(buf: &mut [u8]) -> SbiRet {
+    let ret = console_read(buf);
+    if ret.error != RET_ERR_NOT_SUPPORTED || buf.is_empty() {
+        return ret;
+    }
+    match sbi_call_legacy_0(legacy::LEGACY_CONSOLE_GETCHAR) {
+        usize::MAX => SbiRet::success(0),
+        c => {
+            buf[0] = c as u8;
+            SbiRet::success(1)
+        }
+    }
+}
+
 /// Writes a full string to console using SBI byte-wise API (no log prefix).
 #[inline(always)]
 #[allow(dead_code)]
@@ -64,12 +98,45 @@ pub fn println_str(s: &str) {
 
 /// Writes a byte to the console.
 #[inline(always)]
+#[allow(dead_code)]
 pub fn print_byte(byte: u8) {
     sbi_rt::console_write_byte(byte);
 }
 
-/// Joins two `usize` values into a `u64` value representing a guest physical address (GPA).
+/// The size of the per-vCPU buffer DBCN transfers go through.
+pub const CONSOLE_BUF_SIZE: usize = 256;
+
+/// The per-vCPU buffer DBCN transfers go through, so guests cannot make us allocate.
+pub struct ConsoleBuffer(pub [u8; CONSOLE_BUF_SIZE]);
+
+impl Default for ConsoleBuffer {
+    fn default() -> Self {
+        Self([0; CONSOLE_BUF_SIZE])
+    }
+}
+
+#[inline(always)]
+fn sbi_call_legacy_0(eid: usize) -> usize {
+    let error;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            lateout("a0") error,
+        );
+    }
+    error
+}
+
 #[inline(always)]
-pub fn join_u64(base_lo: usize, base_hi: usize) -> u64 {
-    ((base_hi as u64) << 32) | (base_lo as u64)
+fn sbi_call_legacy_1(eid: usize, arg0: usize) -> usize {
+    let error;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            inlateout("a0") arg0 => error,
+        );
+    }
+    error
 }
@@ -0,0 +1,90 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::ops::RangeInclusive;
+
+use sbi_spec::binary::SbiRet;
+
+use crate::hypercall::HypercallContext;
+
+/// A provider of SBI extensions the vCPU does not implement itself, e.g. vendor extensions for
+/// paravirtual devices.
+///
+/// Register it for a range of extension IDs with
+/// [`RISCVVCpu::register_sbi_extension`](crate::RISCVVCpu::register_sbi_extension), or for all
+/// vCPUs with [`RISCVSbiExtensionIf`](crate::RISCVSbiExtensionIf). Providers only see calls the
+/// VM's [`SbiPolicy`](crate::SbiPolicy) emulates, and never calls to extensions the vCPU
+/// implements itself.
+pub trait SbiExtension: Send + Sync {
+    /// Handles a call of the guest, whose extension ID is in the range the provider is registered
+    /// for.
+    fn handle_ecall(&self, ctx: &HypercallContext) -> SbiExtensionResult;
+
+    /// Returns whether the guest sees the extension `extension_id` as available through
+    /// `probe_extension`. Defaults to `true`.
+    fn probe(&self, extension_id: usize) -> bool {
+        let _ = extension_id;
+        true
+    }
+}
+
+/// What an [`SbiExtension`] did with a call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiExtensionResult {
+    /// Return from the call with the given result in `a0` (error) and `a1` (value).
+    Return(SbiRet),
+    /// Exit to the VMM with [`RISCVExitDetail::SbiCall`](crate::RISCVExitDetail::SbiCall), to
+    /// handle the call outside the vCPU.
+    Exit,
+}
+
+/// The SBI extension providers registered on a vCPU, by the first extension ID of their range.
+#[derive(Default)]
+pub(crate) struct SbiExtensionRegistry {
+    providers: BTreeMap<usize, (usize, Arc<dyn SbiExtension>)>,
+}
+
+impl SbiExtensionRegistry {
+    /// Registers `provider` for the extension IDs in `eids`, returning `false` if the range is
+    /// empty or overlaps that of another provider.
+    pub fn register(
+        &mut self,
+        eids: RangeInclusive<usize>,
+        provider: Arc<dyn SbiExtension>,
+    ) -> bool {
+        let (first, last) = eids.into_inner();
+        if first > last
+            || self.get(first).is_some()
+            || self.providers.range(first..=last).next().is_some()
+        {
+            return false;
+        }
+        self.providers.insert(first, (last, provider));
+        true
+    }
+
+    /// Removes the provider whose range starts at `first_eid`, returning it if there was one.
+    pub fn unregister(&mut self, first_eid: usize) -> Option<Arc<dyn SbiExtension>> {
+        self.providers
+            .remove(&first_eid)
+            .map(|(_, provider)| provider)
+    }
+
+    /// Returns the provider of the extension `extension_id`, if any.
+    pub fn get(&self, extension_id: usize) -> Option<&Arc<dyn SbiExtension>> {
+        let (_, (last, provider)) = self.providers.range(..=extension_id).next_back()?;
+        (extension_id <= *last).then_some(provider)
+    }
+}
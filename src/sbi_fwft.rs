@@ -0,0 +1,221 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The SBI Firmware Features (FWFT) extension.
+//!
+//! The features of the guest map to per-vCPU `hedeleg` and `henvcfg` bits, which are loaded on
+//! `bind`, so a guest never changes the configuration of the host or of other vCPUs.
+
+use sbi_spec::binary::SbiRet;
+
+use crate::{consts::traps::exception, csrs::henvcfg};
+
+/// Extension ID of FWFT, "FWFT" in ASCII.
+pub const EID_FWFT: usize = 0x46574654;
+
+const FID_SET: usize = 0;
+const FID_GET: usize = 1;
+
+/// The `LOCK` flag of `SET`, which makes the feature read-only until the hart is reset.
+const FLAG_LOCK: usize = 1 << 0;
+
+/// Error for a change of a locked feature, `SBI_ERR_DENIED_LOCKED` of SBI v3.0.
+const RET_ERR_DENIED_LOCKED: usize = -14isize as _;
+
+// Feature IDs.
+const MISALIGNED_EXC_DELEG: usize = 0;
+const LANDING_PAD: usize = 1;
+const SHADOW_STACK: usize = 2;
+const DOUBLE_TRAP: usize = 3;
+const PTE_AD_HW_UPDATING: usize = 4;
+const POINTER_MASKING_PMLEN: usize = 5;
+const FEATURE_COUNT: usize = POINTER_MASKING_PMLEN + 1;
+
+/// The exceptions delegated to the guest by `MISALIGNED_EXC_DELEG`.
+const MISALIGNED_EXCEPTIONS: usize =
+    exception::LOAD_ADDR_MISALIGNED | exception::STORE_ADDR_MISALIGNED;
+
+/// The `henvcfg` bits the guest controls through FWFT.
+pub const HENVCFG_MASK: usize =
+    henvcfg::LPE | henvcfg::SSE | henvcfg::DTE | henvcfg::ADUE | henvcfg::PMM;
+
+/// The FWFT state of a vCPU.
+#[derive(Debug, Default)]
+pub(crate) struct Fwft {
+    /// Whether the host firmware delegates misaligned loads and stores to HS-mode, which
+    /// `hedeleg` can only pass on then.
+    misaligned_deleg_supported: bool,
+    /// The `henvcfg` bits of [`HENVCFG_MASK`] other than `PMM` the hart implements.
+    henvcfg_supported: usize,
+    /// Whether the hart supports a pointer masking length of 7 and 16 bits.
+    pmlen_supported: [bool; 2],
+    /// The value of each feature.
+    values: [usize; FEATURE_COUNT],
+    /// The features locked by the guest, as a bitmask of feature IDs.
+    locked: usize,
+}
+
+impl Fwft {
+    /// Creates the FWFT state of a vCPU on the current hart, detecting the features it
+    /// supports.
+    ///
+    /// # Safety
+    ///
+    /// Temporarily writes `henvcfg`, so no vCPU may be bound to the current hart.
+    pub unsafe fn detect() -> Self {
+        let saved = henvcfg::read();
+        let probe = |bits: usize| {
+            unsafe { henvcfg::write(bits) };
+            henvcfg::read()
+        };
+        let henvcfg_supported = probe(HENVCFG_MASK & !henvcfg::PMM);
+        let pmlen_supported = [
+            probe(henvcfg::PMM_PMLEN_7) & henvcfg::PMM == henvcfg::PMM_PMLEN_7,
+            probe(henvcfg::PMM_PMLEN_16) & henvcfg::PMM == henvcfg::PMM_PMLEN_16,
+        ];
+        unsafe { henvcfg::write(saved) };
+        let misaligned = host_get(MISALIGNED_EXC_DELEG);
+
+        Self {
+            misaligned_deleg_supported: misaligned.is_ok() && misaligned.value == 1,
+            henvcfg_supported,
+            pmlen_supported,
+            ..Default::default()
+        }
+    }
+
+    /// Handles an SBI FWFT call of the guest.
+    ///
+    /// Returns whether the knobs changed, see [`Self::hedeleg`] and [`Self::henvcfg`].
+    pub fn handle_ecall(&mut self, function_id: usize, param: [usize; 6]) -> (SbiRet, bool) {
+        match function_id {
+            FID_SET => {
+                let ret = self.set(param[0], param[1], param[2]);
+                (ret, ret.is_ok())
+            }
+            FID_GET => (self.get(param[0]), false),
+            _ => (SbiRet::not_supported(), false),
+        }
+    }
+
+    /// Returns the `hedeleg` bits the guest enabled, on top of [`HEDELEG`](crate::percpu::HEDELEG).
+    pub fn hedeleg(&self) -> usize {
+        if self.values[MISALIGNED_EXC_DELEG] != 0 {
+            MISALIGNED_EXCEPTIONS
+        } else {
+            0
+        }
+    }
+
+    /// Returns the `henvcfg` bits of [`HENVCFG_MASK`] the guest enabled.
+    pub fn henvcfg(&self) -> usize {
+        let mut bits = 0;
+        for (feature, bit) in [
+            (LANDING_PAD, henvcfg::LPE),
+            (SHADOW_STACK, henvcfg::SSE),
+            (DOUBLE_TRAP, henvcfg::DTE),
+            (PTE_AD_HW_UPDATING, henvcfg::ADUE),
+        ] {
+            if self.values[feature] != 0 {
+                bits |= bit;
+            }
+        }
+        bits | match self.values[POINTER_MASKING_PMLEN] {
+            7 => henvcfg::PMM_PMLEN_7,
+            16 => henvcfg::PMM_PMLEN_16,
+            _ => 0,
+        }
+    }
+
+    /// Resets all features to their defaults and unlocks them, e.g. when the hart is restarted.
+    pub fn reset(&mut self) {
+        self.values = [0; FEATURE_COUNT];
+        self.locked = 0;
+    }
+
+    fn set(&mut self, feature: usize, value: usize, flags: usize) -> SbiRet {
+        if let Err(ret) = self.check_supported(feature) {
+            return ret;
+        }
+        if flags & !FLAG_LOCK != 0 {
+            return SbiRet::invalid_param();
+        }
+        if self.locked & (1 << feature) != 0 {
+            return SbiRet {
+                error: RET_ERR_DENIED_LOCKED,
+                value: 0,
+            };
+        }
+
+        let value = if feature == POINTER_MASKING_PMLEN {
+            // Use the smallest supported length that is at least the requested one.
+            match value {
+                0 => 0,
+                1..=7 if self.pmlen_supported[0] => 7,
+                1..=16 if self.pmlen_supported[1] => 16,
+                _ => return SbiRet::invalid_param(),
+            }
+        } else if value <= 1 {
+            value
+        } else {
+            return SbiRet::invalid_param();
+        };
+
+        self.values[feature] = value;
+        if flags & FLAG_LOCK != 0 {
+            self.locked |= 1 << feature;
+        }
+        SbiRet::success(0)
+    }
+
+    fn get(&self, feature: usize) -> SbiRet {
+        match self.check_supported(feature) {
+            Ok(()) => SbiRet::success(self.values[feature]),
+            Err(ret) => ret,
+        }
+    }
+
+    fn check_supported(&self, feature: usize) -> Result<(), SbiRet> {
+        let supported = match feature {
+            MISALIGNED_EXC_DELEG => self.misaligned_deleg_supported,
+            LANDING_PAD => self.henvcfg_supported & henvcfg::LPE != 0,
+            SHADOW_STACK => self.henvcfg_supported & henvcfg::SSE != 0,
+            DOUBLE_TRAP => self.henvcfg_supported & henvcfg::DTE != 0,
+            PTE_AD_HW_UPDATING => self.henvcfg_supported & henvcfg::ADUE != 0,
+            POINTER_MASKING_PMLEN => self.pmlen_supported.contains(&true),
+            // Reserved and platform specific features.
+            _ => return Err(SbiRet::invalid_param()),
+        };
+        if supported {
+            Ok(())
+        } else {
+            Err(SbiRet::not_supported())
+        }
+    }
+}
+
+/// Reads a feature of the host firmware with FWFT `GET`.
+fn host_get(feature: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") EID_FWFT,
+            in("a6") FID_GET,
+            inlateout("a0") feature => error,
+            lateout("a1") value,
+        );
+    }
+    SbiRet { error, value }
+}
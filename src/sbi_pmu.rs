@@ -0,0 +1,404 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Virtualization of the SBI Performance Monitoring Unit extension.
+//!
+//! Guest counter indices are host counter indices. A guest allocates counters from the host
+//! firmware while its vCPU is bound; `unbind` saves and releases them and `bind` allocates them
+//! again, so the physical counters only ever hold the state of the running vCPU. Counters are
+//! configured to count in VS/VU-mode only, so they never see the host or other VMs.
+//!
+//! The fixed `cycle`, `time` and `instret` counters are shared with the host and never handed to
+//! a guest, which can still read them directly.
+
+use core::arch::asm;
+
+use sbi_spec::{
+    binary::{RET_ERR_ALREADY_STARTED, RET_ERR_ALREADY_STOPPED, SbiRet},
+    pmu,
+};
+
+/// The maximum number of counters of a vCPU.
+const MAX_COUNTERS: usize = u64::BITS as usize;
+
+// Flags of `COUNTER_CONFIG_MATCHING`.
+const CFG_SKIP_MATCH: usize = 1 << 0;
+const CFG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_AUTO_START: usize = 1 << 2;
+const CFG_SET_VUINH: usize = 1 << 3;
+const CFG_SET_VSINH: usize = 1 << 4;
+const CFG_SET_UINH: usize = 1 << 5;
+const CFG_SET_SINH: usize = 1 << 6;
+const CFG_SET_MINH: usize = 1 << 7;
+
+// Flags of `COUNTER_START`.
+const START_SET_INIT_VALUE: usize = 1 << 0;
+const START_INIT_SNAPSHOT: usize = 1 << 1;
+
+// Flags of `COUNTER_STOP`.
+const STOP_RESET: usize = 1 << 0;
+const STOP_TAKE_SNAPSHOT: usize = 1 << 1;
+
+/// The CSR number of `cycle`, the first counter CSR.
+const CSR_CYCLE: usize = 0xc00;
+/// The CSR number of `hpmcounter3`, the first programmable counter CSR.
+const CSR_HPMCOUNTER3: usize = 0xc03;
+
+/// The counter indices of `cycle`, `time` and `instret`.
+const FIXED_COUNTERS: usize = 0b111;
+
+/// A counter allocated by the guest.
+#[derive(Clone, Copy, Debug)]
+struct VirtCounter {
+    /// The CSR the counter is read from.
+    csr: usize,
+    /// The event selected by the guest.
+    event_idx: usize,
+    /// The event data selected by the guest.
+    event_data: u64,
+    /// The mode inhibit flags passed to the host firmware.
+    inhibit: usize,
+    /// Whether the guest started the counter.
+    started: bool,
+    /// Whether the counter could not be allocated again in `bind`. It keeps its saved value and
+    /// is allocated again on the next `bind`, unless the guest releases it first.
+    lost: bool,
+    /// The counter value, saved in `unbind`.
+    value: u64,
+}
+
+/// The PMU of a vCPU.
+#[derive(Debug)]
+pub(crate) struct VirtPmu {
+    /// The number of counters of the host, queried on first use.
+    num_counters: Option<usize>,
+    /// The counters allocated by the guest, indexed by counter index.
+    counters: [Option<VirtCounter>; MAX_COUNTERS],
+}
+
+impl Default for VirtPmu {
+    fn default() -> Self {
+        Self {
+            num_counters: None,
+            counters: [None; MAX_COUNTERS],
+        }
+    }
+}
+
+impl VirtPmu {
+    /// Handles an SBI PMU call of the guest.
+    ///
+    /// Must be called with the vCPU bound, as allocated counters live in the physical PMU then.
+    pub fn handle_ecall(&mut self, function_id: usize, param: [usize; 6]) -> SbiRet {
+        match function_id {
+            pmu::NUM_COUNTERS => SbiRet::success(self.num_counters()),
+            pmu::COUNTER_GET_INFO => {
+                if param[0] < self.num_counters() {
+                    sbi_rt::pmu_counter_get_info(param[0])
+                } else {
+                    SbiRet::invalid_param()
+                }
+            }
+            pmu::COUNTER_CONFIG_MATCHING => {
+                self.config_matching(param[0], param[1], param[2], param[3], param[4] as u64)
+            }
+            pmu::COUNTER_START => self.start(param[0], param[1], param[2], param[3] as u64),
+            pmu::COUNTER_STOP => self.stop(param[0], param[1], param[2]),
+            // No firmware counter can be allocated, see `config_matching`.
+            pmu::COUNTER_FW_READ | pmu::COUNTER_FW_READ_HI => SbiRet::invalid_param(),
+            _ => SbiRet::not_supported(),
+        }
+    }
+
+    /// Saves the allocated counters and releases them to the host firmware.
+    pub fn save(&mut self) {
+        for (idx, counter) in self.allocated_mut() {
+            if counter.lost {
+                continue;
+            }
+            counter.value = read_counter(counter.csr);
+            let _ = sbi_rt::pmu_counter_stop(idx, 1, STOP_RESET);
+        }
+    }
+
+    /// Allocates the guest's counters again and restores their values.
+    ///
+    /// A counter the host firmware cannot give back is kept as lost: it stops counting, and
+    /// the guest fails to start it until a later `restore` gets it back.
+    pub fn restore(&mut self) {
+        for idx in 0..MAX_COUNTERS {
+            let Some(counter) = self.counters[idx].as_mut() else {
+                continue;
+            };
+
+            // Restrict the match to the counter the guest owns.
+            let ret = sbi_rt::pmu_counter_config_matching(
+                idx,
+                1,
+                counter.inhibit,
+                counter.event_idx,
+                counter.event_data,
+            );
+            if ret.is_err() || ret.value != idx {
+                warn!(
+                    "failed to restore guest PMU counter {idx}, keeping it as lost: {:#x}",
+                    ret.error
+                );
+                if ret.is_ok() {
+                    let _ = sbi_rt::pmu_counter_stop(ret.value, 1, STOP_RESET);
+                }
+                counter.lost = true;
+                continue;
+            }
+            counter.lost = false;
+
+            // Counters can only be written by starting them.
+            let _ = sbi_rt::pmu_counter_start(idx, 1, START_SET_INIT_VALUE, counter.value);
+            if !counter.started {
+                let _ = sbi_rt::pmu_counter_stop(idx, 1, 0);
+            }
+        }
+    }
+
+    /// Forgets all counters of the guest, e.g. when the hart is restarted.
+    ///
+    /// If the vCPU is bound, the counters must have been released with [`Self::save`] first.
+    pub fn reset(&mut self) {
+        self.counters = [None; MAX_COUNTERS];
+    }
+
+    /// Returns whether the host provides counters to virtualize.
+    pub fn is_available(&mut self) -> bool {
+        self.num_counters() != 0
+    }
+
+    /// Returns the bits of `scountovf` that belong to counters started by the guest.
+    pub fn overflow_mask(&self) -> usize {
+        self.counters
+            .iter()
+            .flatten()
+            .filter(|counter| counter.started && !counter.lost)
+            .fold(0, |mask, counter| mask | 1 << (counter.csr - CSR_CYCLE))
+    }
+
+    fn num_counters(&mut self) -> usize {
+        *self.num_counters.get_or_insert_with(|| {
+            if sbi_rt::probe_extension(sbi_rt::Pmu).is_available() {
+                sbi_rt::pmu_num_counters().min(MAX_COUNTERS)
+            } else {
+                0
+            }
+        })
+    }
+
+    /// Returns the `hcounteren` bits of the counters the guest may read directly: the fixed
+    /// counters and the programmable counters it owns.
+    pub fn counteren(&self) -> usize {
+        self.counters
+            .iter()
+            .flatten()
+            .filter(|counter| !counter.lost)
+            .fold(FIXED_COUNTERS, |mask, counter| {
+                mask | 1 << (counter.csr - CSR_CYCLE)
+            })
+    }
+
+    /// Returns what the guest reads from the programmable counter CSR `csr` while `hcounteren`
+    /// hides it: the saved value of a lost counter, 0 for counters the guest does not own.
+    ///
+    /// Returns `None` if `csr` is not a programmable counter CSR.
+    pub fn hidden_counter_value(&self, csr: usize) -> Option<u64> {
+        if !(CSR_HPMCOUNTER3..CSR_CYCLE + 32).contains(&csr) {
+            return None;
+        }
+        let lost = self
+            .counters
+            .iter()
+            .flatten()
+            .find(|c| c.csr == csr && c.lost);
+        Some(lost.map_or(0, |counter| counter.value))
+    }
+
+    /// Returns the counter indices of the lost counters, see [`Self::restore`].
+    fn lost_counters(&self) -> u64 {
+        (0..MAX_COUNTERS)
+            .filter(|&idx| self.counters[idx].is_some_and(|counter| counter.lost))
+            .fold(0, |counters, idx| counters | 1 << idx)
+    }
+
+    fn allocated_mut(&mut self) -> impl Iterator<Item = (usize, &mut VirtCounter)> {
+        self.counters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(idx, counter)| Some((idx, counter.as_mut()?)))
+    }
+
+    /// Decodes a `counter_idx_base`/`counter_idx_mask` pair into a bitmask of counter indices.
+    ///
+    /// Returns `None` if a selected counter does not exist or, with `allocated`, is not allocated
+    /// by the guest.
+    fn decode_counter_mask(&mut self, base: usize, mask: usize, allocated: bool) -> Option<u64> {
+        let num_counters = self.num_counters();
+        let mut counters = 0u64;
+        for bit in 0..usize::BITS as usize {
+            if mask & (1 << bit) == 0 {
+                continue;
+            }
+            let idx = base.checked_add(bit).filter(|&idx| idx < num_counters)?;
+            if allocated && self.counters[idx].is_none() {
+                return None;
+            }
+            counters |= 1 << idx;
+        }
+        Some(counters)
+    }
+
+    fn config_matching(
+        &mut self,
+        base: usize,
+        mask: usize,
+        flags: usize,
+        event_idx: usize,
+        event_data: u64,
+    ) -> SbiRet {
+        // Firmware events count what the host firmware does on behalf of everyone.
+        if event_idx >> 16 == pmu::event_type::FIRMWARE {
+            return SbiRet::not_supported();
+        }
+        let skip_match = flags & CFG_SKIP_MATCH != 0;
+        match self.decode_counter_mask(base, mask, skip_match) {
+            Some(0) | None => return SbiRet::invalid_param(),
+            Some(_) => {}
+        }
+        // Starting or clearing a fixed counter would change what the host counts.
+        let mask = mask & !FIXED_COUNTERS.checked_shr(base as u32).unwrap_or(0);
+        if mask == 0 {
+            return SbiRet::not_supported();
+        }
+
+        // What the guest sees as S/U-mode is VS/VU-mode, and the host modes are never counted.
+        let mut inhibit = CFG_SET_UINH | CFG_SET_SINH | CFG_SET_MINH;
+        if flags & CFG_SET_UINH != 0 {
+            inhibit |= CFG_SET_VUINH;
+        }
+        if flags & CFG_SET_SINH != 0 {
+            inhibit |= CFG_SET_VSINH;
+        }
+        let host_flags = flags & (CFG_SKIP_MATCH | CFG_CLEAR_VALUE | CFG_AUTO_START) | inhibit;
+
+        let ret =
+            sbi_rt::pmu_counter_config_matching(base, mask, host_flags, event_idx, event_data);
+        if ret.is_err() {
+            return ret;
+        }
+        let idx = ret.value;
+        let info = sbi_rt::pmu_counter_get_info(idx);
+        let csr = info.value & 0xfff;
+        if idx >= MAX_COUNTERS || info.is_err() || !(CSR_HPMCOUNTER3..CSR_CYCLE + 32).contains(&csr)
+        {
+            // Not a programmable hardware counter we can save and restore.
+            let _ = sbi_rt::pmu_counter_stop(idx, 1, STOP_RESET);
+            return SbiRet::not_supported();
+        }
+
+        self.counters[idx] = Some(VirtCounter {
+            csr,
+            event_idx,
+            event_data,
+            inhibit,
+            started: flags & CFG_AUTO_START != 0,
+            lost: false,
+            value: 0,
+        });
+        ret
+    }
+
+    fn start(&mut self, base: usize, mask: usize, flags: usize, initial_value: u64) -> SbiRet {
+        if flags & START_INIT_SNAPSHOT != 0 {
+            return SbiRet::no_shmem();
+        }
+        let Some(counters) = self.decode_counter_mask(base, mask, true) else {
+            return SbiRet::invalid_param();
+        };
+        if counters & self.lost_counters() != 0 {
+            return SbiRet::failed();
+        }
+
+        let ret = sbi_rt::pmu_counter_start(base, mask, flags, initial_value);
+        if ret.is_ok() || ret.error == RET_ERR_ALREADY_STARTED {
+            self.set_started(counters, true);
+        }
+        ret
+    }
+
+    fn stop(&mut self, base: usize, mask: usize, flags: usize) -> SbiRet {
+        if flags & STOP_TAKE_SNAPSHOT != 0 {
+            return SbiRet::no_shmem();
+        }
+        let Some(counters) = self.decode_counter_mask(base, mask, true) else {
+            return SbiRet::invalid_param();
+        };
+
+        // Lost counters are not allocated in the host firmware, only the guest's view is updated.
+        let lost = counters & self.lost_counters();
+        let ret = if lost == 0 {
+            sbi_rt::pmu_counter_stop(base, mask, flags)
+        } else if counters == lost {
+            SbiRet::success(0)
+        } else {
+            sbi_rt::pmu_counter_stop(0, (counters & !lost) as usize, flags)
+        };
+        if ret.is_ok() || ret.error == RET_ERR_ALREADY_STOPPED {
+            self.set_started(counters, false);
+            if flags & STOP_RESET != 0 {
+                for idx in 0..MAX_COUNTERS {
+                    if counters & (1 << idx) != 0 {
+                        self.counters[idx] = None;
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn set_started(&mut self, counters: u64, started: bool) {
+        for (idx, counter) in self.allocated_mut() {
+            if counters & (1 << idx) != 0 {
+                counter.started = started;
+            }
+        }
+    }
+}
+
+/// Reads the counter CSR `csr`, one of `cycle`..=`hpmcounter31`.
+fn read_counter(csr: usize) -> u64 {
+    macro_rules! read_csr {
+        ($($n:literal)*) => {
+            match csr {
+                $($n => {
+                    let value: usize;
+                    unsafe { asm!(concat!("csrr {}, ", $n), out(reg) value) };
+                    value as u64
+                })*
+                _ => 0,
+            }
+        };
+    }
+
+    read_csr!(
+        0xc00 0xc01 0xc02 0xc03 0xc04 0xc05 0xc06 0xc07 0xc08 0xc09 0xc0a 0xc0b 0xc0c 0xc0d 0xc0e
+        0xc0f 0xc10 0xc11 0xc12 0xc13 0xc14 0xc15 0xc16 0xc17 0xc18 0xc19 0xc1a 0xc1b 0xc1c 0xc1d
+        0xc1e 0xc1f
+    )
+}
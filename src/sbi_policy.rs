@@ -0,0 +1,114 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::BTreeMap;
+
+use sbi_spec::binary::SbiRet;
+
+/// What to do with an SBI call of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiAction {
+    /// Handle the call in the vCPU. Calls the vCPU does not implement fail with `NOT_SUPPORTED`.
+    Emulate,
+    /// Pass the call on to the host firmware unchanged.
+    Forward,
+    /// Fail the call with `NOT_SUPPORTED`, logging it to the `riscv_vcpu::audit` target.
+    Deny,
+    /// Exit to the VMM with [`RISCVExitDetail::SbiCall`](crate::RISCVExitDetail::SbiCall).
+    Exit,
+}
+
+/// Decides what to do with the SBI calls of a VM, by extension ID (EID) and optionally function
+/// ID (FID).
+///
+/// A rule for a function takes precedence over a rule for its extension. Calls that match no rule
+/// get the default action, [`SbiAction::Emulate`] unless set otherwise, so nothing reaches the
+/// host firmware unless a rule allows it.
+#[derive(Clone, Debug)]
+pub struct SbiPolicy {
+    /// The rules, keyed by EID and FID, where `None` matches all functions of the extension.
+    rules: BTreeMap<(usize, Option<usize>), SbiAction>,
+    /// The action for calls that match no rule.
+    default_action: SbiAction,
+}
+
+impl Default for SbiPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SbiPolicy {
+    /// Creates a policy that emulates all calls.
+    pub const fn new() -> Self {
+        Self {
+            rules: BTreeMap::new(),
+            default_action: SbiAction::Emulate,
+        }
+    }
+
+    /// Sets the action for calls that match no rule.
+    pub fn with_default_action(mut self, action: SbiAction) -> Self {
+        self.default_action = action;
+        self
+    }
+
+    /// Sets the action for all functions of the extension `eid`.
+    pub fn with_extension(mut self, eid: usize, action: SbiAction) -> Self {
+        self.rules.insert((eid, None), action);
+        self
+    }
+
+    /// Sets the action for the function `fid` of the extension `eid`.
+    pub fn with_function(mut self, eid: usize, fid: usize, action: SbiAction) -> Self {
+        self.rules.insert((eid, Some(fid)), action);
+        self
+    }
+
+    /// Returns the action for a call of the function `fid` of the extension `eid`.
+    pub fn action(&self, eid: usize, fid: usize) -> SbiAction {
+        self.rules
+            .get(&(eid, Some(fid)))
+            .copied()
+            .unwrap_or_else(|| self.extension_action(eid))
+    }
+
+    /// Returns the action for the extension `eid` as a whole, which decides what
+    /// `probe_extension` reports for it.
+    pub fn extension_action(&self, eid: usize) -> SbiAction {
+        self.rules
+            .get(&(eid, None))
+            .copied()
+            .unwrap_or(self.default_action)
+    }
+}
+
+/// Passes an SBI call on to the host firmware.
+pub(crate) fn forward_ecall(eid: usize, fid: usize, param: [usize; 6]) -> SbiRet {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            in("a6") fid,
+            inlateout("a0") param[0] => error,
+            inlateout("a1") param[1] => value,
+            in("a2") param[2],
+            in("a3") param[3],
+            in("a4") param[4],
+            in("a5") param[5],
+        );
+    }
+    SbiRet { error, value }
+}
@@ -0,0 +1,90 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axvisor_api::vmm::MAX_VCPU_NUM;
+use sbi_spec::binary::{HartMask, SbiRet};
+
+use crate::{host, vm::RISCVVmState};
+
+/// A remote fence requested by the guest.
+///
+/// The guest runs in VS-mode, so its `SFENCE.VMA`s become `HFENCE.VVMA`s on the physical harts,
+/// which only affect the VMID currently loaded in `hgatp` there, i.e. this VM's.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum RemoteFence {
+    /// `FENCE.I`.
+    FenceI,
+    /// `SFENCE.VMA` for all address spaces.
+    Vma { start: usize, size: usize },
+    /// `SFENCE.VMA` for a single address space.
+    VmaAsid {
+        start: usize,
+        size: usize,
+        asid: usize,
+    },
+}
+
+impl RemoteFence {
+    /// Asks the host firmware to execute the fence on the given physical harts.
+    fn issue(self, harts: HartMask) -> SbiRet {
+        match self {
+            Self::FenceI => sbi_rt::remote_fence_i(harts),
+            Self::Vma { start, size } => sbi_rt::remote_hfence_vvma(harts, start, size),
+            Self::VmaAsid { start, size, asid } => {
+                sbi_rt::remote_hfence_vvma_asid(harts, start, size, asid)
+            }
+        }
+    }
+}
+
+/// Executes `fence` on the physical harts currently running the vCPUs in `targets`.
+///
+/// vCPUs that are not running need no fence, as `bind` flushes all guest translations anyway. If
+/// the host cannot tell which hart we are running on, the fence is broadcast to all harts.
+pub(crate) fn remote_fence(vm: &RISCVVmState, targets: u64, fence: RemoteFence) -> SbiRet {
+    if host::current_hart_id().is_none() {
+        return fence.issue(HartMask::from_mask_base(0, usize::MAX));
+    }
+
+    let mut harts = [0usize; MAX_VCPU_NUM];
+    let mut count = 0;
+    for vcpu_id in 0..MAX_VCPU_NUM {
+        if targets & (1 << vcpu_id) == 0 {
+            continue;
+        }
+        if let Some(hart) = vm.running_hart(vcpu_id) {
+            harts[count] = hart;
+            count += 1;
+        }
+    }
+
+    let harts = &mut harts[..count];
+    harts.sort_unstable();
+
+    // Hart IDs may be sparse, so issue one call per `usize::BITS` wide window of them.
+    let mut i = 0;
+    while i < harts.len() {
+        let base = harts[i];
+        let mut mask = 0usize;
+        while i < harts.len() && harts[i] - base < usize::BITS as usize {
+            mask |= 1 << (harts[i] - base);
+            i += 1;
+        }
+        let ret = fence.issue(HartMask::from_mask_base(mask, base));
+        if ret.is_err() {
+            return ret;
+        }
+    }
+    SbiRet::success(0)
+}
@@ -0,0 +1,448 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The SBI Supervisor Software Events (SSE) extension.
+//!
+//! Only local events are supported, each vCPU has its own set. An event is delivered when the
+//! vCPU runs, by saving the interrupted context in the event's attributes and entering the
+//! handler the guest registered. `COMPLETE` returns from the handler like `sret` and restores the
+//! saved context. Events do not nest: a pending event waits until the running one completes.
+
+use sbi_spec::binary::SbiRet;
+
+use crate::guest_mem;
+
+/// Extension ID of SSE, "SSE" in ASCII.
+pub const EID_SSE: usize = 0x535345;
+
+const FID_READ_ATTRS: usize = 0;
+const FID_WRITE_ATTRS: usize = 1;
+const FID_REGISTER: usize = 2;
+const FID_UNREGISTER: usize = 3;
+const FID_ENABLE: usize = 4;
+const FID_DISABLE: usize = 5;
+pub const FID_COMPLETE: usize = 6;
+const FID_INJECT: usize = 7;
+const FID_HART_UNMASK: usize = 8;
+const FID_HART_MASK: usize = 9;
+
+/// Error for an operation not allowed in the current state, added in SBI v3.0.
+const RET_ERR_INVALID_STATE: usize = -10isize as _;
+
+// Event IDs of the supported local events.
+const EVENT_LOCAL_HIGH_PRIO_RAS: u32 = 0x0000_0000;
+const EVENT_LOCAL_LOW_PRIO_RAS: u32 = 0x0010_0000;
+const EVENT_LOCAL_SOFTWARE_INJECTED: u32 = 0xffff_0000;
+
+/// The supported events, in the order they are stored.
+const EVENT_IDS: [u32; 3] = [
+    EVENT_LOCAL_HIGH_PRIO_RAS,
+    EVENT_LOCAL_LOW_PRIO_RAS,
+    EVENT_LOCAL_SOFTWARE_INJECTED,
+];
+
+// Event attribute IDs.
+const ATTR_STATUS: usize = 0;
+const ATTR_PRIORITY: usize = 1;
+const ATTR_CONFIG: usize = 2;
+const ATTR_PREFERRED_HART: usize = 3;
+const ATTR_ENTRY_PC: usize = 4;
+const ATTR_ENTRY_ARG: usize = 5;
+const ATTR_INTERRUPTED_SEPC: usize = 6;
+const ATTR_INTERRUPTED_FLAGS: usize = 7;
+const ATTR_INTERRUPTED_A6: usize = 8;
+const ATTR_INTERRUPTED_A7: usize = 9;
+const ATTR_COUNT: usize = ATTR_INTERRUPTED_A7 + 1;
+
+// Bits of the `STATUS` attribute.
+const STATUS_PENDING: usize = 1 << 2;
+const STATUS_INJECT: usize = 1 << 3;
+
+/// The `ONESHOT` bit of the `CONFIG` attribute.
+const CONFIG_ONESHOT: usize = 1 << 0;
+
+// Bits of the `INTERRUPTED_FLAGS` attribute.
+const FLAGS_SSTATUS_SPP: usize = 1 << 0;
+const FLAGS_SSTATUS_SPIE: usize = 1 << 1;
+
+// Bits of `vsstatus`.
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// The state of an event, as reported in the `STATUS` attribute.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum EventState {
+    #[default]
+    Unused = 0,
+    Registered = 1,
+    Enabled = 2,
+    Running = 3,
+}
+
+/// The guest context an event handler interrupted.
+#[derive(Clone, Copy, Debug, Default)]
+struct Interrupted {
+    sepc: usize,
+    flags: usize,
+    a6: usize,
+    a7: usize,
+}
+
+/// An event of a vCPU.
+#[derive(Clone, Copy, Debug, Default)]
+struct Event {
+    state: EventState,
+    pending: bool,
+    priority: u32,
+    config: usize,
+    entry_pc: usize,
+    entry_arg: usize,
+    interrupted: Interrupted,
+}
+
+/// The guest registers that entering and leaving an event handler swap.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SseContext {
+    /// The guest `pc`.
+    pub pc: usize,
+    /// Whether the guest runs in VS-mode rather than VU-mode, i.e. `sstatus.SPP` of the host.
+    pub supervisor: bool,
+    /// `vsstatus`, the guest's `sstatus`.
+    pub vsstatus: usize,
+    /// `vsepc`, the guest's `sepc`.
+    pub vsepc: usize,
+    /// `a6`.
+    pub a6: usize,
+    /// `a7`.
+    pub a7: usize,
+}
+
+/// The SSE state of a vCPU.
+#[derive(Debug)]
+pub(crate) struct SseState {
+    events: [Event; EVENT_IDS.len()],
+    /// Whether the guest masked event delivery with `HART_MASK`.
+    masked: bool,
+}
+
+impl Default for SseState {
+    fn default() -> Self {
+        Self {
+            events: [Event::default(); EVENT_IDS.len()],
+            masked: true,
+        }
+    }
+}
+
+impl SseState {
+    /// Handles an SBI SSE call of the guest, other than `COMPLETE`, see [`Self::complete`].
+    ///
+    /// `hart_id` is the hart ID of the calling vCPU. Must be called with the vCPU bound, as the
+    /// attribute calls access guest memory.
+    pub fn handle_ecall(
+        &mut self,
+        hart_id: usize,
+        function_id: usize,
+        param: [usize; 6],
+    ) -> SbiRet {
+        if matches!(function_id, FID_HART_UNMASK | FID_HART_MASK) {
+            let mask = function_id == FID_HART_MASK;
+            return match (self.masked, mask) {
+                (true, true) => SbiRet::already_stopped(),
+                (false, false) => SbiRet::already_started(),
+                _ => {
+                    self.masked = mask;
+                    SbiRet::success(0)
+                }
+            };
+        }
+
+        let Some(idx) = event_index(param[0]) else {
+            return SbiRet::not_supported();
+        };
+        match function_id {
+            FID_READ_ATTRS => self.read_attrs(idx, hart_id, param[1], param[2], param[3], param[4]),
+            FID_WRITE_ATTRS => self.write_attrs(idx, param[1], param[2], param[3], param[4]),
+            FID_REGISTER => {
+                let event = &mut self.events[idx];
+                if event.state != EventState::Unused {
+                    return invalid_state();
+                }
+                if param[1] % 2 != 0 {
+                    return SbiRet::invalid_address();
+                }
+                event.entry_pc = param[1];
+                event.entry_arg = param[2];
+                event.state = EventState::Registered;
+                SbiRet::success(0)
+            }
+            FID_UNREGISTER => self.transition(
+                idx,
+                &[EventState::Registered, EventState::Enabled],
+                EventState::Unused,
+            ),
+            FID_ENABLE => self.transition(idx, &[EventState::Registered], EventState::Enabled),
+            FID_DISABLE => self.transition(idx, &[EventState::Enabled], EventState::Registered),
+            // Local events can only be injected into the calling hart.
+            FID_INJECT if param[1] != hart_id => SbiRet::invalid_param(),
+            FID_INJECT => {
+                self.events[idx].pending = true;
+                SbiRet::success(0)
+            }
+            _ => SbiRet::not_supported(),
+        }
+    }
+
+    /// Makes the event `event_id` pending, returning `false` if it is not supported.
+    pub fn inject(&mut self, event_id: u32) -> bool {
+        match event_index(event_id as usize) {
+            Some(idx) => {
+                self.events[idx].pending = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Enters the handler of the highest priority pending event, if one can be delivered.
+    ///
+    /// `hart_id` is the hart ID of the vCPU, passed to the handler in `a6`.
+    pub fn deliver(&mut self, hart_id: usize, ctx: &mut SseContext) -> bool {
+        if self.masked
+            || self
+                .events
+                .iter()
+                .any(|event| event.state == EventState::Running)
+        {
+            return false;
+        }
+        // A lower priority value means a higher priority, ties go to the lower event ID, which
+        // is the order events are stored in.
+        let Some(event) = self
+            .events
+            .iter_mut()
+            .filter(|event| event.pending && event.state == EventState::Enabled)
+            .min_by_key(|event| event.priority)
+        else {
+            return false;
+        };
+
+        let mut flags = 0;
+        if ctx.vsstatus & SSTATUS_SPP != 0 {
+            flags |= FLAGS_SSTATUS_SPP;
+        }
+        if ctx.vsstatus & SSTATUS_SPIE != 0 {
+            flags |= FLAGS_SSTATUS_SPIE;
+        }
+        event.interrupted = Interrupted {
+            sepc: ctx.vsepc,
+            flags,
+            a6: ctx.a6,
+            a7: ctx.a7,
+        };
+        event.pending = false;
+        event.state = EventState::Running;
+
+        // Enter the handler as if the guest took a trap at the interrupted `pc`.
+        let mut vsstatus = ctx.vsstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        if ctx.supervisor {
+            vsstatus |= SSTATUS_SPP;
+        }
+        if ctx.vsstatus & SSTATUS_SIE != 0 {
+            vsstatus |= SSTATUS_SPIE;
+        }
+        ctx.vsstatus = vsstatus;
+        ctx.vsepc = ctx.pc;
+        ctx.a6 = hart_id;
+        ctx.a7 = event.entry_arg;
+        ctx.pc = event.entry_pc;
+        ctx.supervisor = true;
+        true
+    }
+
+    /// Handles `COMPLETE`, leaving the handler of the running event like `sret` and restoring
+    /// the context it interrupted.
+    ///
+    /// Fails with the SBI error to return to the guest if no event is running, in which case
+    /// `ctx` is left as is.
+    pub fn complete(&mut self, ctx: &mut SseContext) -> Result<(), SbiRet> {
+        let Some(event) = self
+            .events
+            .iter_mut()
+            .find(|event| event.state == EventState::Running)
+        else {
+            return Err(invalid_state());
+        };
+        event.state = if event.config & CONFIG_ONESHOT != 0 {
+            EventState::Registered
+        } else {
+            EventState::Enabled
+        };
+
+        let interrupted = event.interrupted;
+        ctx.pc = ctx.vsepc;
+        ctx.supervisor = ctx.vsstatus & SSTATUS_SPP != 0;
+        let mut vsstatus = ctx.vsstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        if ctx.vsstatus & SSTATUS_SPIE != 0 {
+            vsstatus |= SSTATUS_SIE;
+        }
+        if interrupted.flags & FLAGS_SSTATUS_SPP != 0 {
+            vsstatus |= SSTATUS_SPP;
+        }
+        if interrupted.flags & FLAGS_SSTATUS_SPIE != 0 {
+            vsstatus |= SSTATUS_SPIE;
+        }
+        ctx.vsstatus = vsstatus;
+        ctx.vsepc = interrupted.sepc;
+        ctx.a6 = interrupted.a6;
+        ctx.a7 = interrupted.a7;
+        Ok(())
+    }
+
+    fn transition(&mut self, idx: usize, from: &[EventState], to: EventState) -> SbiRet {
+        let event = &mut self.events[idx];
+        if !from.contains(&event.state) {
+            return invalid_state();
+        }
+        event.state = to;
+        SbiRet::success(0)
+    }
+
+    fn read_attrs(
+        &self,
+        idx: usize,
+        hart_id: usize,
+        base: usize,
+        count: usize,
+        lo: usize,
+        hi: usize,
+    ) -> SbiRet {
+        let Some(attrs) = attr_range(base, count) else {
+            return SbiRet::invalid_param();
+        };
+        let len = count * size_of::<usize>();
+        let Some(gpa) = guest_mem::shmem_gpa(lo, hi, len, size_of::<usize>()) else {
+            return SbiRet::invalid_address();
+        };
+
+        let event = &self.events[idx];
+        let mut buf = [0u8; ATTR_COUNT * size_of::<usize>()];
+        for (attr, chunk) in attrs.zip(buf.chunks_exact_mut(size_of::<usize>())) {
+            let value = match attr {
+                ATTR_STATUS => {
+                    let mut status = event.state as usize | STATUS_INJECT;
+                    if event.pending {
+                        status |= STATUS_PENDING;
+                    }
+                    status
+                }
+                ATTR_PRIORITY => event.priority as usize,
+                ATTR_CONFIG => event.config,
+                ATTR_PREFERRED_HART => hart_id,
+                ATTR_ENTRY_PC => event.entry_pc,
+                ATTR_ENTRY_ARG => event.entry_arg,
+                ATTR_INTERRUPTED_SEPC => event.interrupted.sepc,
+                ATTR_INTERRUPTED_FLAGS => event.interrupted.flags,
+                ATTR_INTERRUPTED_A6 => event.interrupted.a6,
+                _ => event.interrupted.a7,
+            };
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+
+        if guest_mem::copy_to_guest(&buf[..len], gpa) != len {
+            return SbiRet::invalid_address();
+        }
+        SbiRet::success(0)
+    }
+
+    fn write_attrs(
+        &mut self,
+        idx: usize,
+        base: usize,
+        count: usize,
+        lo: usize,
+        hi: usize,
+    ) -> SbiRet {
+        let Some(attrs) = attr_range(base, count) else {
+            return SbiRet::invalid_param();
+        };
+        let len = count * size_of::<usize>();
+        let Some(gpa) = guest_mem::shmem_gpa(lo, hi, len, size_of::<usize>()) else {
+            return SbiRet::invalid_address();
+        };
+        let mut buf = [0u8; ATTR_COUNT * size_of::<usize>()];
+        if guest_mem::copy_from_guest(&mut buf[..len], gpa) != len {
+            return SbiRet::invalid_address();
+        }
+
+        // Check all attributes before writing any of them.
+        let mut event = self.events[idx];
+        for (attr, chunk) in attrs.zip(buf.chunks_exact(size_of::<usize>())) {
+            let value = usize::from_le_bytes(chunk.try_into().unwrap());
+            match attr {
+                // Only global events can be routed to another hart.
+                ATTR_STATUS | ATTR_PREFERRED_HART | ATTR_ENTRY_PC | ATTR_ENTRY_ARG => {
+                    return SbiRet::denied();
+                }
+                ATTR_PRIORITY | ATTR_CONFIG
+                    if !matches!(event.state, EventState::Unused | EventState::Registered) =>
+                {
+                    return invalid_state();
+                }
+                ATTR_PRIORITY => match u32::try_from(value) {
+                    Ok(priority) => event.priority = priority,
+                    Err(_) => return SbiRet::invalid_param(),
+                },
+                ATTR_CONFIG if value & !CONFIG_ONESHOT != 0 => return SbiRet::invalid_param(),
+                ATTR_CONFIG => event.config = value,
+                // The interrupted context only exists while the handler runs.
+                _ if event.state != EventState::Running => return invalid_state(),
+                ATTR_INTERRUPTED_SEPC => event.interrupted.sepc = value,
+                // The guest harts do not implement the hypervisor extension, so the `hstatus`
+                // flags are always clear.
+                ATTR_INTERRUPTED_FLAGS
+                    if value & !(FLAGS_SSTATUS_SPP | FLAGS_SSTATUS_SPIE) != 0 =>
+                {
+                    return SbiRet::invalid_param();
+                }
+                ATTR_INTERRUPTED_FLAGS => event.interrupted.flags = value,
+                ATTR_INTERRUPTED_A6 => event.interrupted.a6 = value,
+                _ => event.interrupted.a7 = value,
+            }
+        }
+        self.events[idx] = event;
+        SbiRet::success(0)
+    }
+}
+
+/// Returns the index of the event `event_id` in [`EVENT_IDS`], if it is supported.
+fn event_index(event_id: usize) -> Option<usize> {
+    let event_id = u32::try_from(event_id).ok()?;
+    EVENT_IDS.iter().position(|&id| id == event_id)
+}
+
+/// Returns the attribute IDs selected by `base` and `count`, if they all exist.
+fn attr_range(base: usize, count: usize) -> Option<core::ops::Range<usize>> {
+    let end = base.checked_add(count)?;
+    (count != 0 && end <= ATTR_COUNT).then_some(base..end)
+}
+
+fn invalid_state() -> SbiRet {
+    SbiRet {
+        error: RET_ERR_INVALID_STATE,
+        value: 0,
+    }
+}
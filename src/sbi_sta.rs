@@ -0,0 +1,125 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The SBI Steal-time Accounting (STA) extension.
+//!
+//! The guest registers a 64-byte record in its memory, in which it finds the time its vCPU was
+//! ready to run but descheduled by the host. The host reports that time with
+//! [`RISCVVCpu::add_steal_time`](crate::RISCVVCpu::add_steal_time), and the record is updated
+//! before the vCPU enters the guest.
+
+use axaddrspace::GuestPhysAddr;
+use sbi_spec::{binary::SbiRet, sta};
+
+use crate::guest_mem;
+
+/// The alignment of the shared memory record.
+const SHMEM_ALIGN: usize = 64;
+
+// Offsets of the fields of the shared memory record, all little-endian.
+const OFFSET_SEQUENCE: usize = 0;
+const OFFSET_STEAL: usize = 8;
+const OFFSET_PREEMPTED: usize = 16;
+
+/// The steal-time state of a vCPU.
+#[derive(Debug, Default)]
+pub(crate) struct StealTime {
+    /// The guest physical address of the shared memory record, `None` if disabled.
+    shmem: Option<GuestPhysAddr>,
+    /// The last sequence number written to the record.
+    sequence: u32,
+    /// The total steal time in nanoseconds.
+    steal: u64,
+    /// Whether the record is out of date.
+    dirty: bool,
+}
+
+impl StealTime {
+    /// Handles an SBI STA call of the guest.
+    pub fn handle_ecall(&mut self, function_id: usize, param: [usize; 6]) -> SbiRet {
+        match function_id {
+            sta::SET_SHMEM => self.set_shmem(param[0], param[1], param[2]),
+            _ => SbiRet::not_supported(),
+        }
+    }
+
+    /// Adds `ns` nanoseconds to the steal time reported to the guest.
+    pub fn add(&mut self, ns: u64) {
+        self.steal = self.steal.wrapping_add(ns);
+        self.dirty = true;
+    }
+
+    /// Returns the total steal time in nanoseconds.
+    pub fn total(&self) -> u64 {
+        self.steal
+    }
+
+    /// Stops updating the shared memory record, e.g. when the hart is restarted.
+    pub fn disable(&mut self) {
+        self.shmem = None;
+    }
+
+    /// Writes the steal time to the shared memory record if it changed.
+    ///
+    /// Must be called with the vCPU bound, as it writes guest memory.
+    pub fn update(&mut self) {
+        let Some(shmem) = self.shmem else {
+            return;
+        };
+        if !core::mem::take(&mut self.dirty) {
+            return;
+        }
+
+        // An odd sequence number tells the guest that an update is in progress.
+        let written = self.write_sequence(shmem)
+            && write(shmem + OFFSET_STEAL, &self.steal.to_le_bytes())
+            && write(shmem + OFFSET_PREEMPTED, &[0])
+            && self.write_sequence(shmem);
+        if !written {
+            warn!("failed to update steal-time record at {shmem:?}, disabling it");
+            self.shmem = None;
+        }
+    }
+
+    fn set_shmem(&mut self, lo: usize, hi: usize, flags: usize) -> SbiRet {
+        if flags != 0 {
+            return SbiRet::invalid_param();
+        }
+        if lo == usize::MAX && hi == usize::MAX {
+            self.shmem = None;
+            return SbiRet::success(0);
+        }
+        if lo % SHMEM_ALIGN != 0 {
+            return SbiRet::invalid_param();
+        }
+        let Some(gpa) = guest_mem::shmem_gpa(lo, hi, SHMEM_ALIGN, SHMEM_ALIGN) else {
+            return SbiRet::invalid_address();
+        };
+
+        // The guest clears the record before registering it.
+        self.shmem = Some(gpa);
+        self.sequence = 0;
+        self.dirty = true;
+        SbiRet::success(0)
+    }
+
+    fn write_sequence(&mut self, shmem: GuestPhysAddr) -> bool {
+        self.sequence = self.sequence.wrapping_add(1);
+        write(shmem + OFFSET_SEQUENCE, &self.sequence.to_le_bytes())
+    }
+}
+
+fn write(gpa: GuestPhysAddr, bytes: &[u8]) -> bool {
+    guest_mem::copy_to_guest(bytes, gpa) == bytes.len()
+}
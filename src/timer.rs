@@ -0,0 +1,93 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::boxed::Box;
+
+use axvisor_api::{
+    time::{self, CancelToken, Ticks},
+    vmm::{self, VCpuId, VMId},
+};
+
+/// The virtual supervisor timer of a vCPU.
+///
+/// The guest programs its deadline in guest time (`time + htimedelta`). The deadline is converted
+/// to host time and armed as a host timer through `axvisor_api`, which calls
+/// [`vmm::notify_vcpu_timer_expired`] once it fires.
+#[derive(Debug, Default)]
+pub(crate) struct GuestTimer {
+    /// The guest's deadline in guest ticks, `None` if the timer is disarmed.
+    deadline: Option<u64>,
+    /// The same deadline converted to host ticks.
+    host_deadline: Ticks,
+    /// The host timer armed for the current deadline.
+    token: Option<CancelToken>,
+}
+
+impl GuestTimer {
+    /// Programs the guest deadline, replacing any previous one.
+    ///
+    /// `stime_value` is in guest ticks and `htimedelta` is the offset of guest time from host
+    /// time. A deadline of `u64::MAX` disarms the timer, as the SBI spec suggests.
+    pub fn set(&mut self, stime_value: u64, htimedelta: usize, vm_id: VMId, vcpu_id: VCpuId) {
+        self.cancel();
+        if stime_value == u64::MAX {
+            return;
+        }
+
+        self.deadline = Some(stime_value);
+        self.rearm(htimedelta, vm_id, vcpu_id);
+    }
+
+    /// Re-arms the host timer for the current deadline after `htimedelta` changed.
+    pub fn rearm(&mut self, htimedelta: usize, vm_id: VMId, vcpu_id: VCpuId) {
+        let Some(deadline) = self.deadline else {
+            return;
+        };
+
+        self.suspend();
+        self.host_deadline = deadline.wrapping_sub(htimedelta as u64);
+        self.token = Some(time::register_timer(
+            time::ticks_to_time(self.host_deadline),
+            Box::new(move |_| vmm::notify_vcpu_timer_expired(vm_id, vcpu_id)),
+        ));
+    }
+
+    /// Cancels the host timer but keeps the deadline, see [`Self::rearm`].
+    pub fn suspend(&mut self) {
+        if let Some(token) = self.token.take() {
+            time::cancel_timer(token);
+        }
+    }
+
+    /// Disarms the timer.
+    pub fn cancel(&mut self) {
+        self.deadline = None;
+        self.suspend();
+    }
+
+    /// Returns the guest deadline in guest ticks, if armed.
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// Returns the guest deadline in host ticks, if armed.
+    pub fn host_deadline(&self) -> Option<Ticks> {
+        self.deadline.map(|_| self.host_deadline)
+    }
+
+    /// Returns whether the timer is armed and its deadline has passed at host time `now`.
+    pub fn is_expired(&self, now: Ticks) -> bool {
+        self.host_deadline().is_some_and(|deadline| now >= deadline)
+    }
+}
@@ -18,7 +18,11 @@ use riscv_decode::{
     types::{IType, SType},
 };
 use riscv_h::register::{
-    hstatus, htimedelta, hvip,
+    hedeleg::Hedeleg,
+    hgeie,
+    hie::Hie,
+    hstatus, htimedelta,
+    hvip::{self, Hvip},
     vsatp::{self, Vsatp},
     vscause::{self, Vscause},
     vsepc,
@@ -28,16 +32,51 @@ use riscv_h::register::{
     vstval,
     vstvec::{self, Vstvec},
 };
-use rustsbi::{Forward, RustSBI};
-use sbi_spec::{hsm, legacy, srst};
+#[cfg(feature = "legacy")]
+use sbi_spec::legacy;
+use sbi_spec::{
+    base,
+    binary::{RET_ERR_INVALID_PARAM, SbiRet},
+    hsm, pmu, rfnc, spi, srst, sta, susp, time,
+};
 
 use crate::{
-    EID_HVC, RISCVVCpuCreateConfig, consts::traps::irq::S_EXT, guest_mem, regs::*, sbi_console::*,
+    EID_HVC, HartSuspendType, RISCVExitDetail, RISCVVCpuCreateConfig, SBI_CALL_NR_FLAG,
+    SystemResetReason, SystemResetType, SystemSleepType,
+    consts::traps::{
+        interrupt,
+        irq::{S_EXT, S_LCOF, S_TIMER},
+    },
+    csrs::{hcounteren, henvcfg, scountovf, vstimecmp},
+    detect::{detect_sscofpmf_extension, detect_sstc_extension, enable_lcofi_injection},
+    guest_mem, hart_mask, host,
+    hypercall::{HypercallContext, HypercallHandler, HypercallRegistry},
+    percpu::HEDELEG,
+    regs::*,
+    sbi_base,
+    sbi_console::*,
+    sbi_ext::{SbiExtension, SbiExtensionRegistry, SbiExtensionResult},
+    sbi_fwft::{self, EID_FWFT, Fwft},
+    sbi_pmu::VirtPmu,
+    sbi_policy::{self, SbiAction},
+    sbi_rfence::{self, RemoteFence},
+    sbi_sse::{self, EID_SSE, SseContext, SseState},
+    sbi_sta::StealTime,
+    timer::GuestTimer,
+    trap::Exception,
+    vm::{HartState, RISCVVmState},
 };
 
+use alloc::sync::Arc;
 use axaddrspace::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, MappingFlags, device::AccessWidth};
-use axerrno::{AxErrorKind::InvalidData, AxResult};
+use axerrno::{AxErrorKind::InvalidData, AxResult, ax_err};
 use axvcpu::AxVCpuExitReason;
+use axvisor_api::{
+    time::{self as host_time, TimeValue},
+    vmm::{self, MAX_VCPU_NUM, VCpuId, VMId},
+};
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 unsafe extern "C" {
     fn _run_guest(state: *mut VmCpuRegisters);
@@ -46,11 +85,28 @@ unsafe extern "C" {
 const TINST_PSEUDO_STORE: u32 = 0x3020;
 const TINST_PSEUDO_LOAD: u32 = 0x3000;
 
+/// The vector of IPIs sent by the guest, the supervisor software interrupt as seen by the guest.
+const IPI_VECTOR: usize = 1;
+
 #[inline]
 fn instr_is_pseudo(ins: u32) -> bool {
     ins == TINST_PSEUDO_STORE || ins == TINST_PSEUDO_LOAD
 }
 
+/// Maps an interrupt vector to the corresponding bit in `hvip`.
+///
+/// Both the supervisor-level interrupt codes seen by the guest (1, 5, 9) and the
+/// virtual-supervisor-level codes seen by the hypervisor (2, 6, 10) are accepted.
+#[inline]
+fn hvip_bit_of(vector: usize) -> Option<usize> {
+    match vector {
+        1 | 2 => Some(interrupt::VIRTUAL_SUPERVISOR_SOFT),
+        5 | 6 => Some(interrupt::VIRTUAL_SUPERVISOR_TIMER),
+        9 | 10 => Some(interrupt::VIRTUAL_SUPERVISOR_EXTERNAL),
+        _ => None,
+    }
+}
+
 /// The architecture dependent configuration of a `AxArchVCpu`.
 #[derive(Clone, Copy, Debug, Default)]
 pub struct VCpuConfig {}
@@ -58,21 +114,39 @@ pub struct VCpuConfig {}
 #[derive(Default)]
 /// A virtual CPU within a guest
 pub struct RISCVVCpu {
+    vm_id: VMId,
+    vcpu_id: VCpuId,
+    vm_state: Arc<RISCVVmState>,
     regs: VmCpuRegisters,
-    sbi: RISCVVCpuSbi,
-}
-
-#[derive(RustSBI)]
-struct RISCVVCpuSbi {
-    #[rustsbi(console, pmu, fence, reset, info, hsm)]
-    forward: Forward,
-}
-
-impl Default for RISCVVCpuSbi {
-    #[inline]
-    fn default() -> Self {
-        Self { forward: Forward }
-    }
+    timer: GuestTimer,
+    pmu: VirtPmu,
+    steal_time: StealTime,
+    sse: SseState,
+    fwft: Fwft,
+    /// Whether the vCPU is bound to the current physical CPU.
+    bound: bool,
+    /// Whether the guest programs `vstimecmp` directly (Sstc) instead of trapping to SBI.
+    sstc: bool,
+    /// Whether the hart raises local counter overflow interrupts (Sscofpmf).
+    sscofpmf: bool,
+    /// Whether local counter overflow interrupts can be injected into the guest through `hvip`.
+    lcofi_injection: bool,
+    /// The `hvip` bits raised from another hart, merged into `hvip` before entering the guest.
+    raised_irqs: AtomicUsize,
+    /// The `hvip` bits lowered from another hart, merged into `hvip` before entering the guest.
+    lowered_irqs: AtomicUsize,
+    /// The hypercalls handled inside the vCPU.
+    hypercalls: HypercallRegistry,
+    /// The SBI extension providers registered on the vCPU.
+    sbi_extensions: SbiExtensionRegistry,
+    /// Whether the vCPU exited with a hypercall that is not completed yet.
+    hypercall_pending: bool,
+    /// Whether the pending hypercall is a legacy (SBI v0.1) call, which returns in `a0` only.
+    hypercall_legacy: bool,
+    /// The buffer DBCN transfers go through.
+    console_buf: ConsoleBuffer,
+    /// The details of the last exit, see [`RISCVVCpu::exit_detail`].
+    exit_detail: Option<RISCVExitDetail>,
 }
 
 impl axvcpu::AxArchVCpu for RISCVVCpu {
@@ -80,17 +154,36 @@ impl axvcpu::AxArchVCpu for RISCVVCpu {
 
     type SetupConfig = ();
 
-    fn new(_vm_id: usize, _vcpu_id: usize, config: Self::CreateConfig) -> AxResult<Self> {
+    fn new(vm_id: VMId, vcpu_id: VCpuId, config: Self::CreateConfig) -> AxResult<Self> {
         let mut regs = VmCpuRegisters::default();
         // Setup the guest's general purpose registers.
         // `a0` is the hartid
-        regs.guest_regs.gprs.set_reg(GprIndex::A0, config.hart_id);
+        regs.guest_regs.gprs.set_reg(GprIndex::A0, vcpu_id);
         // `a1` is the address of the device tree blob.
         regs.guest_regs.gprs.set_reg(GprIndex::A1, config.dtb_addr);
 
         Ok(Self {
+            vm_id,
+            vcpu_id,
+            vm_state: config.vm_state,
             regs,
-            sbi: RISCVVCpuSbi::default(),
+            timer: GuestTimer::default(),
+            pmu: VirtPmu::default(),
+            steal_time: StealTime::default(),
+            sse: SseState::default(),
+            fwft: Fwft::default(),
+            bound: false,
+            sstc: false,
+            sscofpmf: false,
+            lcofi_injection: false,
+            raised_irqs: AtomicUsize::new(0),
+            lowered_irqs: AtomicUsize::new(0),
+            hypercalls: HypercallRegistry::default(),
+            sbi_extensions: SbiExtensionRegistry::default(),
+            hypercall_pending: false,
+            hypercall_legacy: false,
+            console_buf: ConsoleBuffer::default(),
+            exit_detail: None,
         })
     }
 
@@ -112,6 +205,18 @@ impl axvcpu::AxArchVCpu for RISCVVCpu {
             hstatus.write();
         }
         self.regs.guest_regs.hstatus = hstatus.bits();
+
+        // Let the guest program its timer directly if the hart supports Sstc, otherwise it has to
+        // go through the SBI TIME extension.
+        self.sstc = detect_sstc_extension();
+        if self.sstc {
+            self.regs.virtual_hs_csrs.henvcfg |= henvcfg::STCE;
+            self.regs.vs_csrs.vstimecmp = usize::MAX;
+        }
+        self.sscofpmf = detect_sscofpmf_extension();
+        self.lcofi_injection = self.sscofpmf && enable_lcofi_injection();
+        // No vCPU is bound while the VM is being set up.
+        self.fwft = unsafe { Fwft::detect() };
         Ok(())
     }
 
@@ -126,57 +231,91 @@ impl axvcpu::AxArchVCpu for RISCVVCpu {
     }
 
     fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        // Whatever state the vCPU was in, it is running now.
+        self.vm_state
+            .set_hart_state(self.vcpu_id, HartState::Started);
+        self.exit_detail = None;
+        if core::mem::take(&mut self.hypercall_pending) {
+            // The VMM neither completed nor retried the hypercall, return from it as is.
+            self.advance_pc(4);
+        }
+        self.sync_guest_time();
+        self.steal_time.update();
+        self.deliver_sse();
         unsafe {
             sstatus::clear_sie();
             sie::set_sext();
             sie::set_ssoft();
             sie::set_stimer();
+            if self.sscofpmf {
+                // Catch overflows of guest-owned counters to inject them into the guest.
+                core::arch::asm!("csrs sie, {}", in(reg) interrupt::LOCAL_COUNTER_OVERFLOW);
+            }
         }
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
+            self.merge_remote_irqs();
             _run_guest(&mut self.regs);
         }
         unsafe {
             sie::clear_sext();
             sie::clear_ssoft();
             sie::clear_stimer();
+            if self.sscofpmf {
+                core::arch::asm!("csrc sie, {}", in(reg) interrupt::LOCAL_COUNTER_OVERFLOW);
+            }
             sstatus::set_sie();
         }
+        // The guest clears VSSIP by writing `sip.SSIP`, so `hvip` is only up to date in hardware.
+        self.sync_pending_irqs();
         self.vmexit_handler()
     }
 
     fn bind(&mut self) -> AxResult {
         // Load the vCPU's CSRs from the stored state.
         unsafe {
-            let vsatp = Vsatp::from_bits(self.regs.vs_csrs.vsatp);
-            vsatp.write();
-            let vstvec = Vstvec::from_bits(self.regs.vs_csrs.vstvec);
-            vstvec.write();
-            let vsepc = self.regs.vs_csrs.vsepc;
-            vsepc::write(vsepc);
-            let vstval = self.regs.vs_csrs.vstval;
-            vstval::write(vstval);
-            let htimedelta = self.regs.vs_csrs.htimedelta;
-            htimedelta::write(htimedelta);
-            let vscause = Vscause::from_bits(self.regs.vs_csrs.vscause);
-            vscause.write();
-            let vsscratch = self.regs.vs_csrs.vsscratch;
-            vsscratch::write(vsscratch);
-            let vsstatus = Vsstatus::from_bits(self.regs.vs_csrs.vsstatus);
-            vsstatus.write();
-            let vsie = Vsie::from_bits(self.regs.vs_csrs.vsie);
-            vsie.write();
+            self.load_vs_csrs();
+            self.sync_guest_time();
+            if self.sstc {
+                // The guest timer is driven by `vstimecmp` while the vCPU is bound, drop the
+                // host timer armed for it in `unbind`, and the VSTIP it made the VMM inject if it
+                // fired. Hardware raises VSTIP from `vstimecmp` on its own.
+                if self.timer.is_expired(host_time::current_ticks()) {
+                    self.regs.virtual_hs_csrs.hvip &= !interrupt::VIRTUAL_SUPERVISOR_TIMER;
+                    self.raised_irqs
+                        .fetch_and(!interrupt::VIRTUAL_SUPERVISOR_TIMER, Ordering::AcqRel);
+                }
+                self.timer.cancel();
+                vstimecmp::write(self.regs.vs_csrs.vstimecmp);
+            }
+            let hvip = Hvip::from_bits(self.regs.virtual_hs_csrs.hvip);
+            hvip.write();
+            let hie = Hie::from_bits(self.regs.virtual_hs_csrs.hie);
+            hie.write();
+            let hgeie = self.regs.virtual_hs_csrs.hgeie;
+            hgeie::write(hgeie);
+            let henvcfg = self.regs.virtual_hs_csrs.henvcfg;
+            henvcfg::write(henvcfg);
+            Hedeleg::from_bits(HEDELEG | self.fwft.hedeleg()).write();
             core::arch::asm!(
                 "csrw hgatp, {hgatp}",
                 hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
             );
             core::arch::riscv64::hfence_gvma_all();
+            // Remote fences skip vCPUs that are not running, flush whatever they missed.
+            core::arch::riscv64::hfence_vvma_all();
         }
+        self.pmu.restore();
+        unsafe { hcounteren::write(self.pmu.counteren()) };
+        self.bound = true;
+        self.vm_state
+            .set_running_hart(self.vcpu_id, host::current_hart_id());
         Ok(())
     }
 
     fn unbind(&mut self) -> AxResult {
+        self.pmu.save();
         // Store the vCPU's CSRs to the stored state.
         unsafe {
             self.regs.vs_csrs.vsatp = vsatp::read().bits();
@@ -188,13 +327,29 @@ impl axvcpu::AxArchVCpu for RISCVVCpu {
             self.regs.vs_csrs.vsscratch = vsscratch::read();
             self.regs.vs_csrs.vsstatus = vsstatus::read().bits();
             self.regs.vs_csrs.vsie = vsie::read().bits();
-            core::arch::asm!(
-                "csrr {hgatp}, hgatp",
-                hgatp = out(reg) self.regs.virtual_hs_csrs.hgatp,
-            );
+            self.regs.virtual_hs_csrs.load_from_hw();
+            if self.sstc {
+                self.regs.vs_csrs.vstimecmp = vstimecmp::read();
+            }
+            // Pending virtual interrupts follow the vCPU, so leave nothing behind for the next one.
+            Hvip::from_bits(0).write();
+            hgeie::write(0);
             core::arch::asm!("csrw hgatp, x0");
             core::arch::riscv64::hfence_gvma_all();
         }
+        self.bound = false;
+        self.vm_state.set_running_hart(self.vcpu_id, None);
+        if self.sstc && self.regs.vs_csrs.vstimecmp != usize::MAX {
+            // Nothing compares `vstimecmp` while the vCPU is descheduled, so arm a host timer
+            // to wake it up on time.
+            let htimedelta = self.regs.vs_csrs.htimedelta;
+            self.timer.set(
+                self.regs.vs_csrs.vstimecmp as u64,
+                htimedelta,
+                self.vm_id,
+                self.vcpu_id,
+            );
+        }
         Ok(())
     }
 
@@ -217,10 +372,17 @@ impl axvcpu::AxArchVCpu for RISCVVCpu {
         }
     }
 
-    fn inject_interrupt(&mut self, _vector: usize) -> AxResult {
-        unimplemented!("RISCVVCpu::inject_interrupt is not implemented yet");
+    fn inject_interrupt(&mut self, vector: usize) -> AxResult {
+        let Some(bit) = hvip_bit_of(vector) else {
+            return ax_err!(InvalidInput, "unsupported interrupt vector");
+        };
+        self.set_pending_irqs(bit);
+        Ok(())
     }
 
+    /// Sets `a0`, e.g. the error code of a [`AxVCpuExitReason::Hypercall`].
+    ///
+    /// Use [`RISCVVCpu::complete_hypercall`] to set both `a0` and `a1`.
     fn set_return_value(&mut self, val: usize) {
         self.set_gpr_from_gpr_index(GprIndex::A0, val);
     }
@@ -246,6 +408,463 @@ impl RISCVVCpu {
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Puts a stopped vCPU into the state the SBI spec mandates for a hart started by
+    /// `HART_START`, i.e. to complete a [`AxVCpuExitReason::CpuUp`] of another vCPU.
+    ///
+    /// The vCPU starts at `entry` with `a0` set to its hart ID (the vCPU ID), `a1` set to
+    /// `opaque`, `satp` set to 0 and interrupts disabled. All other registers, the VS-level CSRs
+    /// and pending virtual interrupts are reset, as are the SBI steal-time record, SSE events,
+    /// FWFT features and PMU counters, while guest time is kept.
+    pub fn set_boot_state(&mut self, entry: GuestPhysAddr, opaque: usize) -> AxResult {
+        match self.vm_state.hart_state(self.vcpu_id) {
+            Some(HartState::Stopped | HartState::StartPending) => {}
+            _ => return ax_err!(BadState, "vCPU is not stopped"),
+        }
+
+        self.regs.guest_regs.gprs = GeneralPurposeRegisters::default();
+        self.hypercall_pending = false;
+        self.regs.vs_csrs = GuestVsCsrs {
+            htimedelta: self.regs.vs_csrs.htimedelta,
+            vstimecmp: if self.sstc { usize::MAX } else { 0 },
+            ..Default::default()
+        };
+        self.timer.cancel();
+        self.clear_pending_irqs(usize::MAX);
+        self.steal_time.disable();
+        self.sse = SseState::default();
+        self.fwft.reset();
+        self.apply_fwft();
+        if self.bound {
+            // Release the counters to the host firmware before forgetting them.
+            self.pmu.save();
+        }
+        self.pmu.reset();
+        if self.bound {
+            unsafe {
+                hcounteren::write(self.pmu.counteren());
+                self.load_vs_csrs();
+                if self.sstc {
+                    vstimecmp::write(usize::MAX);
+                }
+            }
+        }
+        self.enter_at(entry.as_usize(), opaque);
+        Ok(())
+    }
+
+    /// Registers a handler for the hypercall (`EID_HVC` ecall) with the given function ID.
+    ///
+    /// The handler runs inside [`axvcpu::AxArchVCpu::run`] without exiting to the VMM, and its
+    /// result is returned to the guest. Hypercalls without a handler exit with
+    /// [`AxVCpuExitReason::Hypercall`]. Fails if a handler is already registered for the ID.
+    pub fn register_hypercall(
+        &mut self,
+        function_id: usize,
+        handler: HypercallHandler,
+    ) -> AxResult {
+        if !self.hypercalls.register(function_id, handler) {
+            return ax_err!(AlreadyExists, "hypercall handler already registered");
+        }
+        Ok(())
+    }
+
+    /// Completes the hypercall the vCPU exited with, returning `ret` to the guest in `a0` (error)
+    /// and `a1` (value).
+    ///
+    /// A legacy (SBI v0.1) call handed to the VMM with [`RISCVExitDetail::SbiCall`] returns
+    /// `ret.error` in `a0` only, leaving `a1` to the guest.
+    ///
+    /// Fail the hypercall by passing an SBI error, e.g. [`SbiRet::not_supported`]. If the VMM
+    /// runs the vCPU without completing or retrying the hypercall, the guest returns from it with
+    /// `a0` and `a1` as they are.
+    pub fn complete_hypercall(&mut self, ret: SbiRet) -> AxResult {
+        if !core::mem::take(&mut self.hypercall_pending) {
+            return ax_err!(BadState, "no hypercall to complete");
+        }
+        if self.hypercall_legacy {
+            self.set_gpr_from_gpr_index(GprIndex::A0, ret.error);
+            self.advance_pc(4);
+        } else {
+            self.sbi_return(ret.error, ret.value);
+        }
+        Ok(())
+    }
+
+    /// Makes the vCPU issue the hypercall it exited with again when it runs next, e.g. when the
+    /// VMM cannot handle it yet.
+    pub fn retry_hypercall(&mut self) -> AxResult {
+        if !core::mem::take(&mut self.hypercall_pending) {
+            return ax_err!(BadState, "no hypercall to retry");
+        }
+        Ok(())
+    }
+
+    /// Removes the handler of the hypercall with the given function ID, returning it if there
+    /// was one.
+    pub fn unregister_hypercall(&mut self, function_id: usize) -> Option<HypercallHandler> {
+        self.hypercalls.unregister(function_id)
+    }
+
+    /// Adds `ns` nanoseconds to the steal time of the vCPU, i.e. the time it was ready to run but
+    /// not scheduled.
+    ///
+    /// The guest sees the total through the SBI STA extension, updated when the vCPU runs next.
+    pub fn add_steal_time(&mut self, ns: u64) {
+        self.steal_time.add(ns);
+    }
+
+    /// Returns the total steal time of the vCPU in nanoseconds.
+    pub fn steal_time(&self) -> u64 {
+        self.steal_time.total()
+    }
+
+    /// Makes the SBI SSE event `event_id` pending, e.g. to report a watchdog timeout or a RAS
+    /// error to the guest.
+    ///
+    /// The event is delivered when the vCPU runs next, once the guest has registered and enabled
+    /// it, unmasked events and left the handler of any other event. The local RAS events
+    /// (`0x0000_0000`, `0x0010_0000`) and the local software event (`0xffff_0000`) are supported.
+    pub fn inject_sse(&mut self, event_id: u32) -> AxResult {
+        if !self.sse.inject(event_id) {
+            return ax_err!(InvalidInput, "unsupported SSE event");
+        }
+        Ok(())
+    }
+
+    /// Registers `provider` for the SBI extensions with IDs in `eids`.
+    ///
+    /// Calls to these extensions run the provider inside [`axvcpu::AxArchVCpu::run`], unless the
+    /// vCPU implements the extension itself. Fails if the range is empty or overlaps that of
+    /// another provider of the vCPU.
+    pub fn register_sbi_extension(
+        &mut self,
+        eids: RangeInclusive<usize>,
+        provider: Arc<dyn SbiExtension>,
+    ) -> AxResult {
+        if !self.sbi_extensions.register(eids, provider) {
+            return ax_err!(AlreadyExists, "SBI extension range already registered");
+        }
+        Ok(())
+    }
+
+    /// Removes the SBI extension provider whose range starts at `first_eid`, returning it if
+    /// there was one.
+    pub fn unregister_sbi_extension(&mut self, first_eid: usize) -> Option<Arc<dyn SbiExtension>> {
+        self.sbi_extensions.unregister(first_eid)
+    }
+
+    /// Returns the RISC-V specific details of the last exit, if it has any.
+    ///
+    /// The details are valid until the next [`axvcpu::AxArchVCpu::run`].
+    pub fn exit_detail(&self) -> Option<RISCVExitDetail> {
+        self.exit_detail
+    }
+
+    /// Clears a pending virtual interrupt, e.g. when an emulated interrupt controller lowers the
+    /// line. Accepts the same vectors as [`axvcpu::AxArchVCpu::inject_interrupt`].
+    pub fn clear_interrupt(&mut self, vector: usize) -> AxResult {
+        let Some(bit) = hvip_bit_of(vector) else {
+            return ax_err!(InvalidInput, "unsupported interrupt vector");
+        };
+        self.clear_pending_irqs(bit);
+        Ok(())
+    }
+
+    /// Returns whether the given virtual interrupt is pending for this vCPU.
+    pub fn is_interrupt_pending(&self, vector: usize) -> bool {
+        hvip_bit_of(vector).is_some_and(|bit| self.pending_interrupts() & bit != 0)
+    }
+
+    /// Returns all virtual interrupts pending for this vCPU, in the layout of `hvip`.
+    pub fn pending_interrupts(&self) -> usize {
+        let lowered = self.lowered_irqs.load(Ordering::Acquire);
+        let raised = self.raised_irqs.load(Ordering::Acquire);
+        self.regs.virtual_hs_csrs.hvip & !lowered | raised
+    }
+
+    /// Returns the deadline the guest programmed for its timer, in guest ticks.
+    pub fn guest_timer_deadline(&self) -> Option<u64> {
+        if self.sstc {
+            let vstimecmp = if self.bound {
+                vstimecmp::read()
+            } else {
+                self.regs.vs_csrs.vstimecmp
+            };
+            (vstimecmp != usize::MAX).then_some(vstimecmp as u64)
+        } else {
+            self.timer.deadline()
+        }
+    }
+
+    /// Returns the current guest time in ticks, i.e. what the guest reads from `time`.
+    ///
+    /// Guest time is kept per VM in [`RISCVVmState`], so all vCPUs of a VM agree on it.
+    pub fn guest_time(&self) -> u64 {
+        self.vm_state.guest_time()
+    }
+
+    /// Sets the current guest time of the VM to `ticks`, e.g. `0` at VM boot, by adjusting
+    /// `htimedelta`.
+    ///
+    /// Calling it on one vCPU is enough, the other vCPUs of the VM pick the new time base up
+    /// when they run next. The guest timer deadline stays in guest time, so it moves along with
+    /// the time base.
+    pub fn set_guest_time(&mut self, ticks: u64) {
+        self.vm_state.set_guest_time(ticks);
+        self.set_htimedelta(self.vm_state.htimedelta());
+    }
+
+    /// Freezes guest time of the VM, e.g. while the VM is paused or being snapshotted.
+    ///
+    /// The first call freezes the time for the whole VM. Call it on every vCPU, as it also
+    /// suspends the vCPU's guest timer, which does not fire while guest time is frozen. The vCPUs
+    /// must not run until [`Self::resume_guest_time`] is called.
+    pub fn pause_guest_time(&mut self) {
+        self.vm_state.pause_guest_time();
+        self.timer.suspend();
+    }
+
+    /// Resumes guest time of the VM from where [`Self::pause_guest_time`] froze it, without a
+    /// jump, and rearms the vCPU's guest timer.
+    ///
+    /// The first call resumes the time for the whole VM. Call it on every vCPU paused with
+    /// [`Self::pause_guest_time`].
+    pub fn resume_guest_time(&mut self) {
+        self.vm_state.resume_guest_time();
+        self.set_htimedelta(self.vm_state.htimedelta());
+    }
+
+    /// Returns whether guest time of the VM is frozen.
+    pub fn is_guest_time_paused(&self) -> bool {
+        self.vm_state.is_guest_time_paused()
+    }
+
+    /// Returns whether the guest programs its timer through `vstimecmp` (Sstc).
+    pub fn has_sstc(&self) -> bool {
+        self.sstc
+    }
+
+    /// Returns the guest's timer deadline in host time, if armed.
+    ///
+    /// With Sstc, this is only known while the vCPU is descheduled.
+    ///
+    /// The guest timer is armed as a host timer through `axvisor_api::time`, so a host that
+    /// multiplexes one hardware timer programs `min(host, guest)` on its own. This is exposed for
+    /// schedulers that want to account for the guest's next wakeup explicitly.
+    pub fn guest_timer_host_deadline(&self) -> Option<TimeValue> {
+        self.timer.host_deadline().map(host_time::ticks_to_time)
+    }
+}
+
+impl RISCVVCpu {
+    /// Writes the stored VS-level CSRs to hardware.
+    unsafe fn load_vs_csrs(&self) {
+        unsafe {
+            let vsatp = Vsatp::from_bits(self.regs.vs_csrs.vsatp);
+            vsatp.write();
+            let vstvec = Vstvec::from_bits(self.regs.vs_csrs.vstvec);
+            vstvec.write();
+            let vsepc = self.regs.vs_csrs.vsepc;
+            vsepc::write(vsepc);
+            let vstval = self.regs.vs_csrs.vstval;
+            vstval::write(vstval);
+            let htimedelta = self.regs.vs_csrs.htimedelta;
+            htimedelta::write(htimedelta);
+            let vscause = Vscause::from_bits(self.regs.vs_csrs.vscause);
+            vscause.write();
+            let vsscratch = self.regs.vs_csrs.vsscratch;
+            vsscratch::write(vsscratch);
+            let vsstatus = Vsstatus::from_bits(self.regs.vs_csrs.vsstatus);
+            vsstatus.write();
+            let vsie = Vsie::from_bits(self.regs.vs_csrs.vsie);
+            vsie.write();
+        }
+    }
+
+    /// Makes the guest continue at `entry` the way SBI starts or resumes a hart: `a0` holds the
+    /// hart ID, `a1` holds `opaque`, `satp` is 0 and interrupts are disabled.
+    fn enter_at(&mut self, entry: usize, opaque: usize) {
+        self.set_gpr_from_gpr_index(GprIndex::A0, self.vcpu_id);
+        self.set_gpr_from_gpr_index(GprIndex::A1, opaque);
+        self.regs.guest_regs.sepc = entry;
+        let mut sstatus = sstatus::Sstatus::from_bits(self.regs.guest_regs.sstatus);
+        sstatus.set_spp(sstatus::SPP::Supervisor);
+        self.regs.guest_regs.sstatus = sstatus.bits();
+
+        // The stored `vsstatus` is only up to date while the vCPU is not bound.
+        let mut vsstatus = if self.bound {
+            vsstatus::read()
+        } else {
+            Vsstatus::from_bits(self.regs.vs_csrs.vsstatus)
+        };
+        vsstatus.set_sie(false);
+        self.regs.vs_csrs.vsstatus = vsstatus.bits();
+        self.regs.vs_csrs.vsatp = 0;
+        if self.bound {
+            unsafe {
+                vsstatus.write();
+                Vsatp::from_bits(0).write();
+                core::arch::riscv64::hfence_vvma_all();
+            }
+        }
+    }
+
+    /// Updates the `henvcfg` and `hedeleg` bits controlled by the guest through FWFT, writing
+    /// them to hardware if the vCPU is bound.
+    fn apply_fwft(&mut self) {
+        let henvcfg = &mut self.regs.virtual_hs_csrs.henvcfg;
+        *henvcfg = *henvcfg & !sbi_fwft::HENVCFG_MASK | self.fwft.henvcfg();
+        if self.bound {
+            unsafe {
+                henvcfg::write(self.regs.virtual_hs_csrs.henvcfg);
+                Hedeleg::from_bits(HEDELEG | self.fwft.hedeleg()).write();
+            }
+        }
+    }
+
+    /// Enters the guest's handler of a pending SSE event, if one can be delivered.
+    fn deliver_sse(&mut self) {
+        let mut ctx = self.sse_context();
+        if self.sse.deliver(self.vcpu_id, &mut ctx) {
+            self.set_sse_context(&ctx);
+        }
+    }
+
+    /// Reads the guest registers swapped by entering and leaving an SSE handler.
+    fn sse_context(&self) -> SseContext {
+        let (vsstatus, vsepc) = if self.bound {
+            (vsstatus::read().bits(), vsepc::read())
+        } else {
+            (self.regs.vs_csrs.vsstatus, self.regs.vs_csrs.vsepc)
+        };
+        let sstatus = sstatus::Sstatus::from_bits(self.regs.guest_regs.sstatus);
+        SseContext {
+            pc: self.regs.guest_regs.sepc,
+            supervisor: sstatus.spp() == sstatus::SPP::Supervisor,
+            vsstatus,
+            vsepc,
+            a6: self.get_gpr(GprIndex::A6),
+            a7: self.get_gpr(GprIndex::A7),
+        }
+    }
+
+    /// Writes back the guest registers swapped by entering and leaving an SSE handler.
+    fn set_sse_context(&mut self, ctx: &SseContext) {
+        self.regs.guest_regs.sepc = ctx.pc;
+        let mut sstatus = sstatus::Sstatus::from_bits(self.regs.guest_regs.sstatus);
+        sstatus.set_spp(if ctx.supervisor {
+            sstatus::SPP::Supervisor
+        } else {
+            sstatus::SPP::User
+        });
+        self.regs.guest_regs.sstatus = sstatus.bits();
+        self.set_gpr_from_gpr_index(GprIndex::A6, ctx.a6);
+        self.set_gpr_from_gpr_index(GprIndex::A7, ctx.a7);
+
+        self.regs.vs_csrs.vsstatus = ctx.vsstatus;
+        self.regs.vs_csrs.vsepc = ctx.vsepc;
+        if self.bound {
+            unsafe {
+                Vsstatus::from_bits(ctx.vsstatus).write();
+                vsepc::write(ctx.vsepc);
+            }
+        }
+    }
+
+    /// Marks the given `hvip` bits as pending, writing them to hardware if the vCPU is bound to
+    /// the current hart.
+    fn set_pending_irqs(&mut self, bits: usize) {
+        if self.runs_here() {
+            self.regs.virtual_hs_csrs.hvip |= bits;
+            self.apply_pending_interrupts();
+        } else {
+            self.lowered_irqs.fetch_and(!bits, Ordering::AcqRel);
+            self.raised_irqs.fetch_or(bits, Ordering::AcqRel);
+        }
+    }
+
+    /// Clears the given `hvip` bits, writing them to hardware if the vCPU is bound to the current
+    /// hart.
+    fn clear_pending_irqs(&mut self, bits: usize) {
+        if self.runs_here() {
+            self.regs.virtual_hs_csrs.hvip &= !bits;
+            self.apply_pending_interrupts();
+        } else {
+            self.raised_irqs.fetch_and(!bits, Ordering::AcqRel);
+            self.lowered_irqs.fetch_or(bits, Ordering::AcqRel);
+        }
+    }
+
+    /// Returns whether the vCPU is bound to the hart this runs on, i.e. whether its `hvip` is
+    /// the hardware one.
+    ///
+    /// Interrupts may be injected from any hart, and without
+    /// [`RISCVVCpuHostIf::current_hart_id`](crate::RISCVVCpuHostIf::current_hart_id) this is
+    /// never known, so they are recorded and merged on the next entry to the guest.
+    fn runs_here(&self) -> bool {
+        self.bound
+            && host::current_hart_id()
+                .is_some_and(|hart| self.vm_state.running_hart(self.vcpu_id) == Some(hart))
+    }
+
+    /// Merges the interrupts raised and lowered from other harts into `hvip`.
+    fn merge_remote_irqs(&mut self) {
+        let lowered = self.lowered_irqs.swap(0, Ordering::AcqRel);
+        let raised = self.raised_irqs.swap(0, Ordering::AcqRel);
+        let hvip = &mut self.regs.virtual_hs_csrs.hvip;
+        *hvip = *hvip & !lowered | raised;
+        self.apply_pending_interrupts();
+    }
+
+    /// Programs the guest timer for `stime_value` (in guest ticks) and lowers VSTIP.
+    fn set_guest_timer(&mut self, stime_value: u64) {
+        if self.sstc {
+            // Guests that still use SBI get the same behaviour as writing `stimecmp` directly.
+            self.regs.vs_csrs.vstimecmp = stime_value as usize;
+            if self.bound {
+                unsafe { vstimecmp::write(stime_value as usize) };
+            }
+            self.clear_pending_irqs(interrupt::VIRTUAL_SUPERVISOR_TIMER);
+            return;
+        }
+
+        let htimedelta = self.regs.vs_csrs.htimedelta;
+        self.timer
+            .set(stime_value, htimedelta, self.vm_id, self.vcpu_id);
+        self.clear_pending_irqs(interrupt::VIRTUAL_SUPERVISOR_TIMER);
+    }
+
+    /// Applies the guest time base of the VM to the vCPU and to hardware.
+    ///
+    /// Only called from `bind` and `run`, i.e. on the hart the vCPU is bound to.
+    fn sync_guest_time(&mut self) {
+        let htimedelta = self.vm_state.htimedelta();
+        if htimedelta != self.regs.vs_csrs.htimedelta {
+            self.set_htimedelta(htimedelta);
+        }
+        unsafe { htimedelta::write(htimedelta) };
+    }
+
+    /// Updates the stored `htimedelta` and moves the armed guest timer along with it.
+    ///
+    /// The VMM may call this from any hart, so hardware is left to [`Self::sync_guest_time`].
+    fn set_htimedelta(&mut self, htimedelta: usize) {
+        self.regs.vs_csrs.htimedelta = htimedelta;
+        self.timer.rearm(htimedelta, self.vm_id, self.vcpu_id);
+    }
+
+    /// Refreshes the stored `hvip` from hardware, picking up the VSSIP the guest cleared.
+    fn sync_pending_irqs(&mut self) {
+        self.regs.virtual_hs_csrs.hvip = hvip::read().bits();
+    }
+
+    /// Writes the vCPU's virtual interrupt state to `hvip`.
+    fn apply_pending_interrupts(&self) {
+        unsafe {
+            Hvip::from_bits(self.regs.virtual_hs_csrs.hvip).write();
+        }
+    }
 }
 
 impl RISCVVCpu {
@@ -253,7 +872,6 @@ impl RISCVVCpu {
         self.regs.trap_csrs.load_from_hw();
 
         let scause = scause::read();
-        use super::trap::Exception;
         use riscv::interrupt::{Interrupt, Trap};
 
         trace!(
@@ -263,6 +881,11 @@ impl RISCVVCpu {
             self.regs.trap_csrs.stval
         );
 
+        // The `riscv` crate does not know the local counter overflow interrupt.
+        if scause.bits() == S_LCOF {
+            return Ok(self.handle_counter_overflow());
+        }
+
         // Try to convert the raw trap cause to a standard RISC-V trap cause.
         let trap: Trap<Interrupt, Exception> = scause.cause().try_into().map_err(|_| {
             error!("Unknown trap cause: scause={:#x}", scause.bits());
@@ -283,186 +906,328 @@ impl RISCVVCpu {
                     function_id,
                     param
                 );
+
+                match self.vm_state.sbi_policy().action(extension_id, function_id) {
+                    SbiAction::Emulate => {}
+                    SbiAction::Forward => {
+                        let ret = sbi_policy::forward_ecall(extension_id, function_id, param);
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    SbiAction::Deny => {
+                        warn!(
+                            target: "riscv_vcpu::audit",
+                            "VM[{}] vCPU[{}] denied SBI call eid {:#x} fid {:#x} param {:#x?}",
+                            self.vm_id, self.vcpu_id, extension_id, function_id, param
+                        );
+                        self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    SbiAction::Exit => {
+                        return Ok(self.sbi_call_exit(extension_id, function_id, param));
+                    }
+                }
+
                 match extension_id {
+                    // Handle Base extension
+                    base::EID_BASE => {
+                        let identity = *self.vm_state.sbi_identity();
+                        let ret =
+                            sbi_base::handle_base_ecall(&identity, function_id, param, |eid| {
+                                self.probe_extension(eid)
+                            });
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
                     // Compatibility with Legacy Extensions.
-                    legacy::LEGACY_SET_TIMER..=legacy::LEGACY_SHUTDOWN => match extension_id {
-                        legacy::LEGACY_SET_TIMER => {
-                            // info!("set timer: {}", param[0]);
-                            sbi_rt::set_timer((param[0]) as u64);
-                            unsafe {
-                                // Clear guest timer interrupt
-                                hvip::clear_vstip();
-                            }
-
-                            self.set_gpr_from_gpr_index(GprIndex::A0, 0);
-                        }
-                        legacy::LEGACY_CONSOLE_PUTCHAR => {
-                            sbi_call_legacy_1(legacy::LEGACY_CONSOLE_PUTCHAR, param[0]);
+                    #[cfg(feature = "legacy")]
+                    legacy::LEGACY_SET_TIMER..=legacy::LEGACY_SHUTDOWN => {
+                        return Ok(self.handle_legacy_ecall(extension_id, param));
+                    }
+                    // Handle Timer extension
+                    time::EID_TIME => match function_id {
+                        time::SET_TIMER => {
+                            self.set_guest_timer(param[0] as u64);
+                            self.sbi_return(RET_SUCCESS, 0);
+                            return Ok(AxVCpuExitReason::Nothing);
                         }
-                        legacy::LEGACY_CONSOLE_GETCHAR => {
-                            let c = sbi_call_legacy_0(legacy::LEGACY_CONSOLE_GETCHAR);
-                            self.set_gpr_from_gpr_index(GprIndex::A0, c);
+                        _ => {
+                            self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
+                            return Ok(AxVCpuExitReason::Nothing);
                         }
-                        legacy::LEGACY_SHUTDOWN => {
-                            // sbi_call_legacy_0(LEGACY_SHUTDOWN)
-                            return Ok(AxVCpuExitReason::SystemDown);
+                    },
+                    // Handle IPI extension
+                    spi::EID_SPI => match function_id {
+                        spi::SEND_IPI => {
+                            match hart_mask::decode_hart_mask(param[0], param[1], self.vcpu_num()) {
+                                Some(targets) => {
+                                    self.sbi_return(RET_SUCCESS, 0);
+                                    return Ok(Self::ipi_exit(targets));
+                                }
+                                None => {
+                                    self.sbi_return(RET_ERR_INVALID_PARAM, 0);
+                                    return Ok(AxVCpuExitReason::Nothing);
+                                }
+                            }
                         }
                         _ => {
-                            warn!(
-                                "Unsupported SBI legacy extension id {extension_id:#x} function id {function_id:#x}"
-                            );
+                            self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
+                            return Ok(AxVCpuExitReason::Nothing);
                         }
                     },
+                    // Handle RFENCE extension
+                    rfnc::EID_RFNC => {
+                        let fence = match function_id {
+                            rfnc::REMOTE_FENCE_I => Some(RemoteFence::FenceI),
+                            rfnc::REMOTE_SFENCE_VMA => Some(RemoteFence::Vma {
+                                start: param[2],
+                                size: param[3],
+                            }),
+                            rfnc::REMOTE_SFENCE_VMA_ASID => Some(RemoteFence::VmaAsid {
+                                start: param[2],
+                                size: param[3],
+                                asid: param[4],
+                            }),
+                            // The guest harts do not implement the hypervisor extension, so the
+                            // HFENCE variants are not supported.
+                            _ => None,
+                        };
+                        let ret = match fence {
+                            Some(fence) => match hart_mask::decode_hart_mask(
+                                param[0],
+                                param[1],
+                                self.vcpu_num(),
+                            ) {
+                                Some(targets) => {
+                                    sbi_rfence::remote_fence(&self.vm_state, targets, fence)
+                                }
+                                None => SbiRet::invalid_param(),
+                            },
+                            None => SbiRet::not_supported(),
+                        };
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
                     // Handle HSM extension
                     hsm::EID_HSM => match function_id {
                         hsm::HART_START => {
                             let hartid = a[0];
                             let start_addr = a[1];
                             let opaque = a[2];
-                            self.advance_pc(4);
-                            return Ok(AxVCpuExitReason::CpuUp {
-                                target_cpu: hartid as _,
-                                entry_point: GuestPhysAddr::from(start_addr),
-                                arg: opaque as _,
-                            });
+                            // The VMM owns the guest memory layout, only the alignment of the
+                            // start address can be checked here.
+                            let ret = if hartid >= self.vcpu_num() {
+                                SbiRet::invalid_param()
+                            } else if start_addr % 2 != 0 {
+                                SbiRet::invalid_address()
+                            } else {
+                                match self.vm_state.transition_hart_state(
+                                    hartid,
+                                    HartState::Stopped,
+                                    HartState::StartPending,
+                                ) {
+                                    Ok(()) => {
+                                        // The VMM brings up the target with `set_boot_state`.
+                                        self.sbi_return(RET_SUCCESS, 0);
+                                        return Ok(AxVCpuExitReason::CpuUp {
+                                            target_cpu: hartid as _,
+                                            entry_point: GuestPhysAddr::from(start_addr),
+                                            arg: opaque as _,
+                                        });
+                                    }
+                                    Err(_) => SbiRet::already_available(),
+                                }
+                            };
+                            self.sbi_return(ret.error, ret.value);
+                            return Ok(AxVCpuExitReason::Nothing);
                         }
                         hsm::HART_STOP => {
+                            self.vm_state
+                                .set_hart_state(self.vcpu_id, HartState::Stopped);
                             return Ok(AxVCpuExitReason::CpuDown { _state: 0 });
                         }
+                        hsm::HART_GET_STATUS => {
+                            let ret = match self.vm_state.hart_state(a[0]) {
+                                Some(state) if a[0] < self.vcpu_num() => {
+                                    SbiRet::success(state as _)
+                                }
+                                _ => SbiRet::invalid_param(),
+                            };
+                            self.sbi_return(ret.error, ret.value);
+                            return Ok(AxVCpuExitReason::Nothing);
+                        }
                         hsm::HART_SUSPEND => {
-                            // Todo: support these parameters.
-                            let _suspend_type = a[0];
-                            let _resume_addr = a[1];
-                            let _opaque = a[2];
+                            let resume_addr = a[1];
+                            let opaque = a[2];
+                            let suspend_type = match HartSuspendType::from_raw(a[0]) {
+                                Ok(HartSuspendType::NonRetentive) if resume_addr % 2 != 0 => {
+                                    Err(SbiRet::invalid_address())
+                                }
+                                ret => ret,
+                            };
+                            let suspend_type = match suspend_type {
+                                Ok(suspend_type) => suspend_type,
+                                Err(ret) => {
+                                    self.sbi_return(ret.error, ret.value);
+                                    return Ok(AxVCpuExitReason::Nothing);
+                                }
+                            };
+
+                            match suspend_type {
+                                // Return from the call once woken up.
+                                HartSuspendType::Retentive => self.sbi_return(RET_SUCCESS, 0),
+                                // Restart at `resume_addr` once woken up.
+                                HartSuspendType::NonRetentive => self.enter_at(resume_addr, opaque),
+                            }
+                            self.vm_state
+                                .set_hart_state(self.vcpu_id, HartState::Suspended);
+                            self.exit_detail = Some(RISCVExitDetail::HartSuspend(suspend_type));
                             return Ok(AxVCpuExitReason::Halt);
                         }
-                        _ => todo!(),
+                        _ => {
+                            self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
+                            return Ok(AxVCpuExitReason::Nothing);
+                        }
                     },
-                    // Handle hypercall
-                    EID_HVC => {
-                        self.advance_pc(4);
-                        return Ok(AxVCpuExitReason::Hypercall {
-                            nr: function_id as _,
-                            args: [
-                                param[0] as _,
-                                param[1] as _,
-                                param[2] as _,
-                                param[3] as _,
-                                param[4] as _,
-                                param[5] as _,
-                            ],
-                        });
+                    // Features are configured per vCPU, see `Fwft`.
+                    EID_FWFT => {
+                        let (ret, changed) = self.fwft.handle_ecall(function_id, param);
+                        if changed {
+                            self.apply_fwft();
+                        }
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
                     }
-                    // Debug Console Extension
-                    EID_DBCN => match function_id {
-                        // Write from memory region to debug console.
-                        FID_CONSOLE_WRITE => {
-                            let num_bytes = param[0];
-                            let gpa = join_u64(param[1], param[2]);
-
-                            if num_bytes == 0 {
-                                self.sbi_return(RET_SUCCESS, 0);
-                                return Ok(AxVCpuExitReason::Nothing);
+                    // Events are delivered per vCPU, see `SseState`.
+                    EID_SSE => {
+                        if function_id == sbi_sse::FID_COMPLETE {
+                            let mut ctx = self.sse_context();
+                            match self.sse.complete(&mut ctx) {
+                                // Resumes the interrupted context, not the caller.
+                                Ok(()) => self.set_sse_context(&ctx),
+                                Err(ret) => self.sbi_return(ret.error, ret.value),
                             }
-
-                            let mut buf = alloc::vec![0u8; num_bytes];
-                            let copied = guest_mem::copy_from_guest(
-                                &mut buf,
-                                GuestPhysAddr::from(gpa as usize),
+                        } else {
+                            let ret = self.sse.handle_ecall(self.vcpu_id, function_id, param);
+                            self.sbi_return(ret.error, ret.value);
+                        }
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    // Steal time is accounted per vCPU, see `StealTime`.
+                    sta::EID_STA => {
+                        let ret = self.steal_time.handle_ecall(function_id, param);
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    // Counters are allocated per vCPU, see `VirtPmu`.
+                    pmu::EID_PMU => {
+                        let ret = self.pmu.handle_ecall(function_id, param);
+                        // The guest may have allocated or released counters.
+                        unsafe { hcounteren::write(self.pmu.counteren()) };
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    // Handle hypercall
+                    EID_HVC => {
+                        // Keep hypercall numbers apart from the SBI calls handed to the VMM.
+                        if function_id as u64 & SBI_CALL_NR_FLAG != 0 {
+                            self.sbi_return(RET_ERR_INVALID_PARAM, 0);
+                            return Ok(AxVCpuExitReason::Nothing);
+                        }
+                        if let Some(handler) = self.hypercalls.get(function_id) {
+                            let ctx = HypercallContext::new(
+                                self.vm_id,
+                                self.vcpu_id,
+                                extension_id,
+                                function_id,
+                                param,
                             );
-
-                            if copied == buf.len() {
-                                let ret = console_write(&buf);
-                                self.sbi_return(ret.error, ret.value);
-                            } else {
-                                self.sbi_return(RET_ERR_FAILED, 0);
-                            }
-
+                            let ret = handler(&ctx);
+                            self.sbi_return(ret.error, ret.value);
                             return Ok(AxVCpuExitReason::Nothing);
                         }
-                        // Read to memory region from debug console.
-                        FID_CONSOLE_READ => {
-                            let num_bytes = param[0];
-                            let gpa = join_u64(param[1], param[2]);
-
-                            if num_bytes == 0 {
-                                self.sbi_return(RET_SUCCESS, 0);
-                                return Ok(AxVCpuExitReason::Nothing);
-                            }
-
-                            let mut buf = alloc::vec![0u8; num_bytes];
-                            let ret = console_read(&mut buf);
-
-                            if ret.is_ok() && ret.value <= buf.len() {
-                                let copied = guest_mem::copy_to_guest(
-                                    &buf[..ret.value],
-                                    GuestPhysAddr::from(gpa as usize),
-                                );
-                                if copied == ret.value {
-                                    self.sbi_return(RET_SUCCESS, ret.value);
-                                } else {
-                                    self.sbi_return(RET_ERR_FAILED, 0);
+                        // Stay on the ecall until the VMM completes the hypercall.
+                        self.hypercall_pending = true;
+                        self.hypercall_legacy = false;
+                        return Ok(Self::hypercall_exit(function_id as _, param));
+                    }
+                    // Debug Console Extension
+                    EID_DBCN => {
+                        let ret = self.handle_dbcn(function_id, param);
+                        self.sbi_return(ret.error, ret.value);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                    srst::EID_SRST => match function_id {
+                        srst::SYSTEM_RESET => {
+                            match (
+                                SystemResetType::from_raw(param[0]),
+                                SystemResetReason::from_raw(param[1]),
+                            ) {
+                                (Ok(reset_type), Ok(reason)) => {
+                                    // The VMM tells shutdown from reboot by the exit detail.
+                                    self.exit_detail =
+                                        Some(RISCVExitDetail::SystemReset { reset_type, reason });
+                                    return Ok(AxVCpuExitReason::SystemDown);
+                                }
+                                (Err(ret), _) | (_, Err(ret)) => {
+                                    self.sbi_return(ret.error, ret.value);
+                                    return Ok(AxVCpuExitReason::Nothing);
                                 }
-                            } else {
-                                self.sbi_return(ret.error, ret.value);
                             }
-
-                            return Ok(AxVCpuExitReason::Nothing);
                         }
-                        // Write a single byte to debug console.
-                        FID_CONSOLE_WRITE_BYTE => {
-                            let byte = (param[0] & 0xff) as u8;
-                            print_byte(byte);
-                            self.sbi_return(RET_SUCCESS, 0);
-                            return Ok(AxVCpuExitReason::Nothing);
-                        }
-                        // Unknown FID.
                         _ => {
                             self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
                             return Ok(AxVCpuExitReason::Nothing);
                         }
                     },
-                    srst::EID_SRST => match function_id {
-                        srst::SYSTEM_RESET => {
-                            let reset_type = param[0];
-                            if reset_type == srst::RESET_TYPE_SHUTDOWN as _ {
-                                // Shutdown the system.
-                                return Ok(AxVCpuExitReason::SystemDown);
-                            } else {
-                                unimplemented!("Unsupported reset type {}", reset_type);
-                            }
+                    susp::EID_SUSP => match function_id {
+                        susp::SUSPEND => {
+                            let resume_addr = a[1];
+                            let opaque = a[2];
+                            let ret = match SystemSleepType::from_raw(a[0]) {
+                                Ok(_) if resume_addr % 2 != 0 => SbiRet::invalid_address(),
+                                Ok(_) if !self.other_vcpus_stopped() => SbiRet::denied(),
+                                Ok(sleep_type) => {
+                                    self.enter_at(resume_addr, opaque);
+                                    self.vm_state
+                                        .set_hart_state(self.vcpu_id, HartState::Suspended);
+                                    self.exit_detail = Some(RISCVExitDetail::SystemSuspend {
+                                        sleep_type,
+                                        resume_addr: GuestPhysAddr::from(resume_addr),
+                                        opaque,
+                                    });
+                                    return Ok(AxVCpuExitReason::Halt);
+                                }
+                                Err(ret) => ret,
+                            };
+                            self.sbi_return(ret.error, ret.value);
+                            return Ok(AxVCpuExitReason::Nothing);
                         }
                         _ => {
                             self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
                             return Ok(AxVCpuExitReason::Nothing);
                         }
                     },
-                    // By default, forward the SBI call to the RustSBI implementation.
-                    // See [`RISCVVCpuSbi`].
-                    _ => {
-                        let ret = self.sbi.handle_ecall(extension_id, function_id, param);
-                        if ret.is_err() {
-                            warn!(
-                                "forward ecall eid {:#x} fid {:#x} param {:#x?} err {:#x} value {:#x}",
-                                extension_id, function_id, param, ret.error, ret.value
-                            );
-                        }
-                        self.set_gpr_from_gpr_index(GprIndex::A0, ret.error);
-                        self.set_gpr_from_gpr_index(GprIndex::A1, ret.value);
-                    }
+                    _ => {}
                 };
 
-                self.advance_pc(4);
-                Ok(AxVCpuExitReason::Nothing)
+                // Anything else is up to the registered SBI extension providers.
+                Ok(self.handle_extension_ecall(extension_id, function_id, param))
             }
             Trap::Interrupt(Interrupt::SupervisorTimer) => {
-                // Enable guest timer interrupt
-                unsafe {
-                    hvip::set_vstip();
-                    sie::set_stimer();
+                if self.timer.is_expired(host_time::current_ticks()) {
+                    // The guest's deadline has passed, deliver the timer interrupt to the guest.
+                    // If the host timer is still pending, we will trap again right after
+                    // re-entering the guest, and hand it to the host then.
+                    self.timer.cancel();
+                    self.set_pending_irqs(interrupt::VIRTUAL_SUPERVISOR_TIMER);
+                    Ok(AxVCpuExitReason::Nothing)
+                } else {
+                    // The host's timer fired, let the host scheduler handle it.
+                    Ok(AxVCpuExitReason::ExternalInterrupt {
+                        vector: S_TIMER as _,
+                    })
                 }
-
-                Ok(AxVCpuExitReason::Nothing)
             }
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 // 9 == Interrupt::SupervisorExternal
@@ -475,6 +1240,16 @@ impl RISCVVCpu {
             Trap::Exception(
                 gpf @ (Exception::LoadGuestPageFault | Exception::StoreGuestPageFault),
             ) => self.handle_guest_page_fault(gpf == Exception::StoreGuestPageFault),
+            Trap::Exception(
+                misaligned @ (Exception::LoadMisaligned | Exception::StoreMisaligned),
+            ) => {
+                // The host firmware delegates these, but the guest did not ask for them through
+                // FWFT. There is no firmware left to emulate the access, so the guest gets the
+                // exception as if it ran on bare hardware.
+                self.redirect_exception(misaligned, self.regs.trap_csrs.stval);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            Trap::Exception(Exception::VirtualInstruction) => Ok(self.handle_virtual_instruction()),
             _ => {
                 panic!(
                     "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
@@ -486,6 +1261,328 @@ impl RISCVVCpu {
         }
     }
 
+    /// Emulates a guest read of a counter CSR that `hcounteren` hides from it, and makes any other
+    /// virtual instruction an illegal instruction to the guest.
+    fn handle_virtual_instruction(&mut self) -> AxVCpuExitReason {
+        let mut instr = self.regs.trap_csrs.stval;
+        if instr == 0 {
+            let sepc = GuestVirtAddr::from(self.regs.guest_regs.sepc);
+            instr = guest_mem::fetch_guest_instruction(sepc) as usize;
+        }
+        let rd = instr >> 7 & 0x1f;
+        let funct3 = instr >> 12 & 0x7;
+        let rs1 = instr >> 15 & 0x1f;
+        let csr = instr >> 20 & 0xfff;
+        // `csrrs`/`csrrc` with `x0` and `csrrsi`/`csrrci` with 0 only read the CSR.
+        let read_only = instr & 0x7f == 0x73 && matches!(funct3, 2 | 3 | 6 | 7) && rs1 == 0;
+        match self.pmu.hidden_counter_value(csr) {
+            Some(value) if read_only => {
+                if let Some(rd) = GprIndex::from_raw(rd as u32).filter(|_| rd != 0) {
+                    self.set_gpr_from_gpr_index(rd, value as usize);
+                }
+                self.advance_pc(4);
+            }
+            _ => self.redirect_exception(Exception::IllegalInstruction, instr),
+        }
+        AxVCpuExitReason::Nothing
+    }
+
+    /// Makes the guest take `exception` with `tval` as if it had been delegated to it.
+    ///
+    /// Only called on VM exits, i.e. with the vCPU bound.
+    fn redirect_exception(&mut self, exception: Exception, tval: usize) {
+        let mut sstatus = sstatus::Sstatus::from_bits(self.regs.guest_regs.sstatus);
+        unsafe {
+            let mut vsstatus = vsstatus::read();
+            vsstatus.set_spp(sstatus.spp() == sstatus::SPP::Supervisor);
+            vsstatus.set_spie(vsstatus.sie());
+            vsstatus.set_sie(false);
+            vsstatus.write();
+            vsepc::write(self.regs.guest_regs.sepc);
+            Vscause::from_bits(exception as usize).write();
+            vstval::write(tval);
+            // Exceptions always go to the base address, whatever the mode of `vstvec`.
+            self.regs.guest_regs.sepc = vstvec::read().bits() & !0b11;
+        }
+        sstatus.set_spp(sstatus::SPP::Supervisor);
+        self.regs.guest_regs.sstatus = sstatus.bits();
+    }
+
+    /// Injects LCOFI into the guest if one of its counters overflowed, and leaves overflows of
+    /// host counters to the host.
+    ///
+    /// Without `hvien` support, overflows of guest counters cannot be reported to the guest and
+    /// are dropped.
+    fn handle_counter_overflow(&mut self) -> AxVCpuExitReason {
+        let overflowed = scountovf::read();
+        let guest = self.pmu.overflow_mask();
+        if overflowed & guest != 0 && self.lcofi_injection {
+            self.set_pending_irqs(interrupt::LOCAL_COUNTER_OVERFLOW);
+        }
+        if overflowed & !guest != 0 {
+            return AxVCpuExitReason::ExternalInterrupt {
+                vector: S_LCOF as _,
+            };
+        }
+        unsafe {
+            core::arch::asm!("csrc sip, {}", in(reg) interrupt::LOCAL_COUNTER_OVERFLOW);
+        }
+        AxVCpuExitReason::Nothing
+    }
+
+    /// Returns the number of vCPUs of the VM, i.e. the number of harts the guest can address.
+    fn vcpu_num(&self) -> usize {
+        vmm::vcpu_num(self.vm_id).unwrap_or(MAX_VCPU_NUM)
+    }
+
+    /// Handles a legacy (SBI v0.1) call, which returns an error code or value in `a0` only.
+    #[cfg(feature = "legacy")]
+    fn handle_legacy_ecall(&mut self, extension_id: usize, param: [usize; 6]) -> AxVCpuExitReason {
+        use sbi_spec::binary::RET_ERR_INVALID_ADDRESS;
+
+        let ret = match extension_id {
+            legacy::LEGACY_SET_TIMER => {
+                self.set_guest_timer(param[0] as u64);
+                RET_SUCCESS
+            }
+            legacy::LEGACY_CONSOLE_PUTCHAR => {
+                match host::console_write(self.vm_id, self.vcpu_id, &[param[0] as u8]) {
+                    Ok(1) => RET_SUCCESS,
+                    _ => SbiRet::failed().error,
+                }
+            }
+            legacy::LEGACY_CONSOLE_GETCHAR => {
+                let mut byte = [0u8];
+                match host::console_read(self.vm_id, self.vcpu_id, &mut byte) {
+                    Ok(1) => byte[0] as usize,
+                    _ => usize::MAX,
+                }
+            }
+            legacy::LEGACY_CLEAR_IPI => {
+                // Returns whether an IPI was pending.
+                let pending = self.is_interrupt_pending(IPI_VECTOR);
+                self.clear_pending_irqs(interrupt::VIRTUAL_SUPERVISOR_SOFT);
+                pending as usize
+            }
+            legacy::LEGACY_SEND_IPI
+            | legacy::LEGACY_REMOTE_FENCE_I
+            | legacy::LEGACY_REMOTE_SFENCE_VMA
+            | legacy::LEGACY_REMOTE_SFENCE_VMA_ASID => {
+                // The hart mask is passed by its address in guest virtual memory.
+                match hart_mask::read_legacy_hart_mask(param[0], self.vcpu_num()) {
+                    Ok(Some(targets)) => {
+                        let fence = match extension_id {
+                            legacy::LEGACY_SEND_IPI => {
+                                self.set_gpr_from_gpr_index(GprIndex::A0, RET_SUCCESS);
+                                self.advance_pc(4);
+                                return Self::ipi_exit(targets);
+                            }
+                            legacy::LEGACY_REMOTE_FENCE_I => RemoteFence::FenceI,
+                            legacy::LEGACY_REMOTE_SFENCE_VMA => RemoteFence::Vma {
+                                start: param[1],
+                                size: param[2],
+                            },
+                            _ => RemoteFence::VmaAsid {
+                                start: param[1],
+                                size: param[2],
+                                asid: param[3],
+                            },
+                        };
+                        sbi_rfence::remote_fence(&self.vm_state, targets, fence).error
+                    }
+                    Ok(None) => RET_ERR_INVALID_PARAM,
+                    Err(()) => RET_ERR_INVALID_ADDRESS,
+                }
+            }
+            legacy::LEGACY_SHUTDOWN => {
+                self.exit_detail = Some(RISCVExitDetail::SystemReset {
+                    reset_type: SystemResetType::Shutdown,
+                    reason: SystemResetReason::NoReason,
+                });
+                return AxVCpuExitReason::SystemDown;
+            }
+            _ => RET_ERR_NOT_SUPPORTED,
+        };
+        self.set_gpr_from_gpr_index(GprIndex::A0, ret);
+        self.advance_pc(4);
+        AxVCpuExitReason::Nothing
+    }
+
+    /// Handles a debug console (DBCN) call.
+    ///
+    /// Transfers go through the fixed-size per-vCPU console buffer, so longer requests are
+    /// shortened, as DBCN allows.
+    fn handle_dbcn(&mut self, function_id: usize, param: [usize; 6]) -> SbiRet {
+        let (vm_id, vcpu_id) = (self.vm_id, self.vcpu_id);
+        match function_id {
+            FID_CONSOLE_WRITE | FID_CONSOLE_READ => {
+                let num_bytes = param[0];
+                let Some(gpa) = guest_mem::shmem_gpa(param[1], param[2], num_bytes, 1) else {
+                    return SbiRet::invalid_param();
+                };
+                let buf = &mut self.console_buf.0[..num_bytes.min(CONSOLE_BUF_SIZE)];
+                if buf.is_empty() {
+                    return SbiRet::success(0);
+                }
+
+                if function_id == FID_CONSOLE_WRITE {
+                    if guest_mem::copy_from_guest(buf, gpa) != buf.len() {
+                        return SbiRet::invalid_param();
+                    }
+                    match host::console_write(vm_id, vcpu_id, buf) {
+                        Ok(written) => SbiRet::success(written.min(buf.len())),
+                        Err(_) => SbiRet::failed(),
+                    }
+                } else {
+                    let read = match host::console_read(vm_id, vcpu_id, buf) {
+                        Ok(read) => read.min(buf.len()),
+                        Err(_) => return SbiRet::failed(),
+                    };
+                    if guest_mem::copy_to_guest(&buf[..read], gpa) != read {
+                        return SbiRet::invalid_param();
+                    }
+                    SbiRet::success(read)
+                }
+            }
+            FID_CONSOLE_WRITE_BYTE => {
+                let byte = (param[0] & 0xff) as u8;
+                match host::console_write(vm_id, vcpu_id, &[byte]) {
+                    Ok(1) => SbiRet::success(0),
+                    _ => SbiRet::failed(),
+                }
+            }
+            _ => SbiRet::not_supported(),
+        }
+    }
+
+    /// Returns whether the guest can use the given SBI extension under the VM's
+    /// [`SbiPolicy`](crate::SbiPolicy), as reported by `probe_extension`.
+    fn probe_extension(&mut self, extension_id: usize) -> bool {
+        match self.vm_state.sbi_policy().extension_action(extension_id) {
+            SbiAction::Emulate => self.emulates_extension(extension_id),
+            SbiAction::Forward => {
+                let ret = sbi_policy::forward_ecall(
+                    base::EID_BASE,
+                    base::PROBE_EXTENSION,
+                    [extension_id, 0, 0, 0, 0, 0],
+                );
+                ret.is_ok() && ret.value != 0
+            }
+            SbiAction::Deny => false,
+            SbiAction::Exit => true,
+        }
+    }
+
+    /// Handles a call to an SBI extension the vCPU does not implement itself through the
+    /// registered [`SbiExtension`] providers, failing with `NOT_SUPPORTED` if there is none.
+    fn handle_extension_ecall(
+        &mut self,
+        extension_id: usize,
+        function_id: usize,
+        param: [usize; 6],
+    ) -> AxVCpuExitReason {
+        let local = self.sbi_extensions.get(extension_id).cloned();
+        let provider: &dyn SbiExtension = match (&local, host::sbi_extension(extension_id)) {
+            (Some(provider), _) => provider.as_ref(),
+            (None, Some(provider)) => provider,
+            (None, None) => {
+                self.sbi_return(RET_ERR_NOT_SUPPORTED, 0);
+                return AxVCpuExitReason::Nothing;
+            }
+        };
+
+        let ctx = HypercallContext::new(self.vm_id, self.vcpu_id, extension_id, function_id, param);
+        match provider.handle_ecall(&ctx) {
+            SbiExtensionResult::Return(ret) => {
+                self.sbi_return(ret.error, ret.value);
+                AxVCpuExitReason::Nothing
+            }
+            SbiExtensionResult::Exit => self.sbi_call_exit(extension_id, function_id, param),
+        }
+    }
+
+    /// Returns whether the SBI extension `extension_id` is available to the guest if emulated,
+    /// i.e. implemented by the vCPU itself or by a registered provider.
+    fn emulates_extension(&mut self, extension_id: usize) -> bool {
+        match extension_id {
+            #[cfg(feature = "legacy")]
+            legacy::LEGACY_SET_TIMER..=legacy::LEGACY_SHUTDOWN => true,
+            base::EID_BASE
+            | time::EID_TIME
+            | spi::EID_SPI
+            | rfnc::EID_RFNC
+            | hsm::EID_HSM
+            | srst::EID_SRST
+            | susp::EID_SUSP
+            | sta::EID_STA
+            | EID_SSE
+            | EID_FWFT
+            | EID_DBCN
+            | EID_HVC => true,
+            pmu::EID_PMU => self.pmu.is_available(),
+            _ => match self.sbi_extensions.get(extension_id) {
+                Some(provider) => provider.probe(extension_id),
+                None => host::sbi_extension(extension_id)
+                    .is_some_and(|provider| provider.probe(extension_id)),
+            },
+        }
+    }
+
+    /// Returns whether all other vCPUs of the VM are in the STOPPED state.
+    fn other_vcpus_stopped(&self) -> bool {
+        (0..self.vcpu_num())
+            .filter(|&vcpu_id| vcpu_id != self.vcpu_id)
+            .all(|vcpu_id| {
+                self.vm_state
+                    .hart_state(vcpu_id)
+                    .is_none_or(|state| state == HartState::Stopped)
+            })
+    }
+
+    /// Builds the exit that asks the VMM to send an IPI to the vCPUs in `targets`.
+    ///
+    /// Like GICv3 SGIs on AArch64, `target_cpu_aux` carries a bitmask of target vCPU IDs (relative
+    /// to `target_cpu`, which is always 0). The VMM is expected to inject `vector` into each of
+    /// them, which raises VSSIP.
+    fn ipi_exit(targets: u64) -> AxVCpuExitReason {
+        AxVCpuExitReason::SendIPI {
+            target_cpu: 0,
+            target_cpu_aux: targets,
+            send_to_all: false,
+            send_to_self: false,
+            vector: IPI_VECTOR as _,
+        }
+    }
+
+    /// Hands an SBI call to the VMM with [`RISCVExitDetail::SbiCall`], to be completed like a
+    /// hypercall. The exit's `nr` is encoded as described at [`SBI_CALL_NR_FLAG`].
+    fn sbi_call_exit(
+        &mut self,
+        extension_id: usize,
+        function_id: usize,
+        param: [usize; 6],
+    ) -> AxVCpuExitReason {
+        self.hypercall_pending = true;
+        self.hypercall_legacy = extension_id <= sbi_spec::legacy::LEGACY_SHUTDOWN;
+        self.exit_detail = Some(RISCVExitDetail::SbiCall {
+            extension_id,
+            function_id,
+        });
+        let nr = SBI_CALL_NR_FLAG
+            | (extension_id as u64 & 0x7fff_ffff) << 32
+            | function_id as u32 as u64;
+        Self::hypercall_exit(nr, param)
+    }
+
+    /// Builds the exit that hands the call with the given `nr` and arguments to the VMM, see
+    /// [`Self::complete_hypercall`].
+    fn hypercall_exit(nr: u64, param: [usize; 6]) -> AxVCpuExitReason {
+        AxVCpuExitReason::Hypercall {
+            nr,
+            args: param.map(|arg| arg as _),
+        }
+    }
+
     #[inline]
     fn sbi_return(&mut self, a0: usize, a1: usize) {
         self.set_gpr_from_gpr_index(GprIndex::A0, a0);
@@ -652,29 +1749,3 @@ impl RISCVVCpu {
         })
     }
 }
-
-#[inline(always)]
-fn sbi_call_legacy_0(eid: usize) -> usize {
-    let error;
-    unsafe {
-        core::arch::asm!(
-            "ecall",
-            in("a7") eid,
-            lateout("a0") error,
-        );
-    }
-    error
-}
-
-#[inline(always)]
-fn sbi_call_legacy_1(eid: usize, arg0: usize) -> usize {
-    let error;
-    unsafe {
-        core::arch::asm!(
-            "ecall",
-            in("a7") eid,
-            inlateout("a0") arg0 => error,
-        );
-    }
-    error
-}
@@ -0,0 +1,238 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use axvisor_api::{
+    time,
+    vmm::{MAX_VCPU_NUM, VCpuId},
+};
+use sbi_spec::hsm::hart_state;
+
+use crate::{sbi_base::SbiIdentity, sbi_policy::SbiPolicy};
+
+/// Marks a vCPU that is not bound to any physical hart.
+const NO_HART: usize = usize::MAX;
+
+/// Marks guest time as running.
+const NOT_PAUSED: u64 = u64::MAX;
+
+/// The SBI HSM state of a hart, i.e. of a vCPU as seen by the guest.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartState {
+    /// The hart is running.
+    Started = hart_state::STARTED,
+    /// The hart is not running, and can be started with `HART_START`.
+    Stopped = hart_state::STOPPED,
+    /// Another hart requested to start the hart, and it has not run yet.
+    StartPending = hart_state::START_PENDING,
+    /// The hart requested to stop itself.
+    StopPending = hart_state::STOP_PENDING,
+    /// The hart is suspended.
+    Suspended = hart_state::SUSPENDED,
+    /// The hart requested to suspend itself.
+    SuspendPending = hart_state::SUSPEND_PENDING,
+    /// The hart is resuming from suspend.
+    ResumePending = hart_state::RESUME_PENDING,
+}
+
+impl HartState {
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            hart_state::STARTED => Self::Started,
+            hart_state::START_PENDING => Self::StartPending,
+            hart_state::STOP_PENDING => Self::StopPending,
+            hart_state::SUSPENDED => Self::Suspended,
+            hart_state::SUSPEND_PENDING => Self::SuspendPending,
+            hart_state::RESUME_PENDING => Self::ResumePending,
+            _ => Self::Stopped,
+        }
+    }
+}
+
+/// State shared by all vCPUs of a VM.
+///
+/// SBI calls such as RFENCE address other harts of the guest, so the vCPUs of a VM need a common
+/// view of each other. Create one per VM and pass the same instance to all of its vCPUs, with
+/// [`RISCVVCpuCreateConfig::new`](crate::RISCVVCpuCreateConfig::new).
+#[derive(Debug)]
+pub struct RISCVVmState {
+    /// The physical hart each vCPU is bound to, `NO_HART` if it is not bound.
+    running_on: [AtomicUsize; MAX_VCPU_NUM],
+    /// The HSM state of each vCPU, as a raw [`HartState`].
+    hart_states: [AtomicUsize; MAX_VCPU_NUM],
+    /// The SBI implementation the guest sees.
+    sbi_identity: SbiIdentity,
+    /// What to do with the SBI calls of the guest.
+    sbi_policy: SbiPolicy,
+    /// The offset of guest time from host time, applied by every vCPU as `htimedelta`.
+    htimedelta: AtomicUsize,
+    /// The guest time at which guest time was paused, `NOT_PAUSED` if it runs.
+    paused_guest_time: AtomicU64,
+}
+
+impl Default for RISCVVmState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RISCVVmState {
+    /// Creates the shared state for a VM with no vCPU bound and all vCPUs stopped.
+    pub const fn new() -> Self {
+        Self {
+            running_on: [const { AtomicUsize::new(NO_HART) }; MAX_VCPU_NUM],
+            hart_states: [const { AtomicUsize::new(HartState::Stopped as _) }; MAX_VCPU_NUM],
+            sbi_identity: SbiIdentity::new(),
+            sbi_policy: SbiPolicy::new(),
+            htimedelta: AtomicUsize::new(0),
+            paused_guest_time: AtomicU64::new(NOT_PAUSED),
+        }
+    }
+
+    /// Sets the SBI implementation the guest sees.
+    pub fn with_sbi_identity(mut self, sbi_identity: SbiIdentity) -> Self {
+        self.sbi_identity = sbi_identity;
+        self
+    }
+
+    /// Sets what to do with the SBI calls of the guest, see [`SbiPolicy`].
+    pub fn with_sbi_policy(mut self, sbi_policy: SbiPolicy) -> Self {
+        self.sbi_policy = sbi_policy;
+        self
+    }
+
+    /// Returns the SBI implementation the guest sees.
+    pub fn sbi_identity(&self) -> &SbiIdentity {
+        &self.sbi_identity
+    }
+
+    /// Returns what to do with the SBI calls of the guest.
+    pub fn sbi_policy(&self) -> &SbiPolicy {
+        &self.sbi_policy
+    }
+
+    /// Returns the current guest time in ticks, i.e. what the guest reads from `time` on every
+    /// hart.
+    pub fn guest_time(&self) -> u64 {
+        match self.paused_guest_time.load(Ordering::Acquire) {
+            NOT_PAUSED => time::current_ticks().wrapping_add(self.htimedelta() as u64),
+            paused => paused,
+        }
+    }
+
+    /// Returns whether guest time is frozen.
+    pub fn is_guest_time_paused(&self) -> bool {
+        self.paused_guest_time.load(Ordering::Acquire) != NOT_PAUSED
+    }
+
+    /// Returns the offset of guest time from host time, as `htimedelta`.
+    pub(crate) fn htimedelta(&self) -> usize {
+        self.htimedelta.load(Ordering::Acquire)
+    }
+
+    /// Sets the current guest time to `ticks`, or the time it resumes at if it is paused.
+    pub(crate) fn set_guest_time(&self, ticks: u64) {
+        let paused =
+            self.paused_guest_time
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |paused| {
+                    (paused != NOT_PAUSED).then_some(ticks)
+                });
+        if paused.is_err() {
+            self.htimedelta.store(
+                ticks.wrapping_sub(time::current_ticks()) as usize,
+                Ordering::Release,
+            );
+        }
+    }
+
+    /// Freezes guest time, unless it is already frozen.
+    pub(crate) fn pause_guest_time(&self) {
+        let now = self.guest_time();
+        let _ = self.paused_guest_time.compare_exchange(
+            NOT_PAUSED,
+            now,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// Resumes guest time from where it was frozen, without a jump.
+    pub(crate) fn resume_guest_time(&self) {
+        let paused = self.paused_guest_time.load(Ordering::Acquire);
+        if paused == NOT_PAUSED {
+            return;
+        }
+        // Only the resume that unfreezes guest time moves the time base.
+        if self
+            .paused_guest_time
+            .compare_exchange(paused, NOT_PAUSED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.htimedelta.store(
+                paused.wrapping_sub(time::current_ticks()) as usize,
+                Ordering::Release,
+            );
+        }
+    }
+
+    /// Returns the physical hart the given vCPU is bound to, if any.
+    ///
+    /// This is only known if the host implements
+    /// [`RISCVVCpuHostIf::current_hart_id`](crate::RISCVVCpuHostIf::current_hart_id).
+    pub fn running_hart(&self, vcpu_id: VCpuId) -> Option<usize> {
+        let hart = self.running_on.get(vcpu_id)?.load(Ordering::Acquire);
+        (hart != NO_HART).then_some(hart)
+    }
+
+    /// Records the physical hart the given vCPU is bound to.
+    pub(crate) fn set_running_hart(&self, vcpu_id: VCpuId, hart: Option<usize>) {
+        if let Some(slot) = self.running_on.get(vcpu_id) {
+            slot.store(hart.unwrap_or(NO_HART), Ordering::Release);
+        }
+    }
+
+    /// Returns the HSM state of the given vCPU.
+    ///
+    /// A vCPU becomes [`HartState::Started`] when it runs, and stops or suspends at the guest's
+    /// request. Returns `None` if `vcpu_id` is out of range.
+    pub fn hart_state(&self, vcpu_id: VCpuId) -> Option<HartState> {
+        let state = self.hart_states.get(vcpu_id)?.load(Ordering::Acquire);
+        Some(HartState::from_raw(state))
+    }
+
+    /// Sets the HSM state of the given vCPU, e.g. to stop a vCPU from the VMM.
+    pub fn set_hart_state(&self, vcpu_id: VCpuId, state: HartState) {
+        if let Some(slot) = self.hart_states.get(vcpu_id) {
+            slot.store(state as _, Ordering::Release);
+        }
+    }
+
+    /// Moves the given vCPU from HSM state `from` to `to`, failing with the current state if it
+    /// is not `from`.
+    pub(crate) fn transition_hart_state(
+        &self,
+        vcpu_id: VCpuId,
+        from: HartState,
+        to: HartState,
+    ) -> Result<(), HartState> {
+        let Some(slot) = self.hart_states.get(vcpu_id) else {
+            return Err(HartState::Stopped);
+        };
+        slot.compare_exchange(from as _, to as _, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(HartState::from_raw)
+    }
+}